@@ -6,10 +6,10 @@ use criterion::{black_box, criterion_group, criterion_main, Criterion};
 use futures::future;
 use tokio_util::sync::CancellationToken;
 use tower::{service_fn, BoxError, Service, ServiceExt};
-use tower_rpc::transport::codec::{serde_codec, Codec, CodecStream, SerdeCodec};
+use tower_rpc::transport::codec::{Codec, CodecStream};
 use tower_rpc::transport::ipc::{self, IpcSecurity, OnConflict, SecurityAttributes, ServerId};
 use tower_rpc::transport::{tcp, Bind, Connect};
-use tower_rpc::{Client, MakeHandler, Request, Server};
+use tower_rpc::{pipeline_codec, Client, MakeHandler, Request, Server};
 
 pub fn bench_calls(c: &mut Criterion) {
     bench_inner(c).expect("Failed to run")
@@ -40,7 +40,7 @@ fn bench_inner(c: &mut Criterion) -> Result<(), BoxError> {
         .await?;
 
         let server = Server::pipeline(
-            CodecStream::new(transport, SerdeCodec::<usize, usize>::new(Codec::Bincode)),
+            CodecStream::new(transport, pipeline_codec::<usize, usize>(Codec::Bincode)),
             service_fn(Handler::make),
         );
         context.add_service(server);
@@ -53,10 +53,9 @@ fn bench_inner(c: &mut Criterion) -> Result<(), BoxError> {
                 ipc::Connection::connect(ipc::ConnectionParams::new(ServerId("test")).unwrap())
                     .await
                     .unwrap();
-            let mut client = Client::new(serde_codec::<usize, usize>(
-                client_transport,
-                Codec::Bincode,
-            ))
+            let mut client = Client::new(
+                pipeline_codec::<usize, usize>(Codec::Bincode).client(client_transport),
+            )
             .create_pipeline();
             let mut i = 0;
             tokio::spawn(async move {
@@ -90,7 +89,7 @@ fn bench_inner(c: &mut Criterion) -> Result<(), BoxError> {
         let transport = tcp::Endpoint::bind("127.0.0.1:8080".parse()?).await?;
 
         let server = Server::pipeline(
-            CodecStream::new(transport, SerdeCodec::<usize, usize>::new(Codec::Bincode)),
+            CodecStream::new(transport, pipeline_codec::<usize, usize>(Codec::Bincode)),
             service_fn(Handler::make),
         );
         context.add_service(server);
@@ -103,10 +102,9 @@ fn bench_inner(c: &mut Criterion) -> Result<(), BoxError> {
             let client_transport = tcp::Connection::connect("127.0.0.1:8080".parse().unwrap())
                 .await
                 .expect("Failed to connect");
-            let mut client = Client::new(serde_codec::<usize, usize>(
-                client_transport,
-                Codec::Bincode,
-            ))
+            let mut client = Client::new(
+                pipeline_codec::<usize, usize>(Codec::Bincode).client(client_transport),
+            )
             .create_pipeline();
             let mut i = 0;
             tokio::spawn(async move {
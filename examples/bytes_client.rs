@@ -5,12 +5,14 @@ use tower::{BoxError, Service, ServiceExt};
 use tower_rpc::transport::codec::length_delimited_codec;
 use tower_rpc::transport::ipc::{self, ServerId};
 use tower_rpc::transport::Connect;
-use tower_rpc::Client;
+use tower_rpc::{Client, RawFrames};
+
 #[tokio::main]
 pub async fn main() -> Result<(), BoxError> {
     let client_transport =
         ipc::Connection::connect(ipc::ConnectionParams::new(ServerId("test"))?).await?;
-    let mut client = Client::new(length_delimited_codec(client_transport)).create_pipeline();
+    let mut client =
+        Client::new(RawFrames::client(length_delimited_codec(client_transport))).create_pipeline();
 
     loop {
         println!("Send: ping");
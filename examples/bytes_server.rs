@@ -1,14 +1,14 @@
 use std::task::{Context, Poll};
 
 use background_service::BackgroundServiceManager;
-use bytes::{Bytes, BytesMut};
-use futures::future;
+use bytes::Bytes;
+use futures::{future, TryStreamExt};
 use tokio_util::sync::CancellationToken;
 use tower::{service_fn, BoxError};
 use tower_rpc::transport::codec::{CodecStream, LengthDelimitedCodec};
 use tower_rpc::transport::ipc::{self, IpcSecurity, OnConflict, SecurityAttributes, ServerId};
 use tower_rpc::transport::Bind;
-use tower_rpc::{MakeHandler, Request, Server};
+use tower_rpc::{MakeHandler, RawFrames, Request, Server};
 
 #[tokio::main]
 pub async fn main() -> Result<(), BoxError> {
@@ -25,7 +25,7 @@ pub async fn main() -> Result<(), BoxError> {
     .await?;
 
     let server = Server::pipeline(
-        CodecStream::new(transport, LengthDelimitedCodec),
+        CodecStream::new(transport, LengthDelimitedCodec).map_ok(RawFrames::server),
         service_fn(Handler::make),
     );
     let mut context = manager.get_context();
@@ -38,7 +38,7 @@ pub async fn main() -> Result<(), BoxError> {
 #[derive(Default)]
 struct Handler;
 
-impl tower::Service<Request<BytesMut>> for Handler {
+impl tower::Service<Request<Bytes>> for Handler {
     type Response = Bytes;
     type Error = BoxError;
     type Future = future::Ready<Result<Self::Response, Self::Error>>;
@@ -47,7 +47,7 @@ impl tower::Service<Request<BytesMut>> for Handler {
         Poll::Ready(Ok(()))
     }
 
-    fn call(&mut self, req: Request<BytesMut>) -> Self::Future {
+    fn call(&mut self, req: Request<Bytes>) -> Self::Future {
         println!(
             "Request: {}",
             String::from_utf8(req.value.to_vec()).expect("Invalid string")
@@ -1,10 +1,10 @@
 use std::time::Duration;
 
 use tower::{BoxError, Service, ServiceExt};
-use tower_rpc::transport::codec::{Codec, SerdeCodec};
+use tower_rpc::transport::codec::Codec;
 use tower_rpc::transport::ipc::{self, ServerId};
 use tower_rpc::transport::Connect;
-use tower_rpc::Client;
+use tower_rpc::{pipeline_codec, Client};
 
 #[tokio::main]
 pub async fn main() -> Result<(), BoxError> {
@@ -12,7 +12,7 @@ pub async fn main() -> Result<(), BoxError> {
         ipc::Connection::connect(ipc::ConnectionParams::new(ServerId("test"))?).await?;
 
     let mut client =
-        Client::new(SerdeCodec::<usize, usize>::new(Codec::Bincode).client(client_transport))
+        Client::new(pipeline_codec::<usize, usize>(Codec::Bincode).client(client_transport))
             .create_pipeline();
     let mut i = 0;
     loop {
@@ -4,20 +4,19 @@ use rand::rngs::SmallRng;
 use rand::{Rng, SeedableRng};
 use tower::buffer::Buffer;
 use tower::{BoxError, Service, ServiceExt};
-use tower_rpc::transport::codec::{Codec, SerdeCodec};
+use tower_rpc::transport::codec::Codec;
 use tower_rpc::transport::ipc::{self, ServerId};
 use tower_rpc::transport::Connect;
-use tower_rpc::{Client, Tagged};
+use tower_rpc::{multiplex_codec, Client};
 
 #[tokio::main]
 pub async fn main() -> Result<(), BoxError> {
     let client_transport =
         ipc::Connection::connect(ipc::ConnectionParams::new(ServerId("test"))?).await?;
 
-    let client = Client::new(
-        SerdeCodec::<Tagged<usize>, Tagged<usize>>::new(Codec::Bincode).client(client_transport),
-    )
-    .create_multiplex();
+    let client =
+        Client::new(multiplex_codec::<usize, usize>(Codec::Bincode).client(client_transport))
+            .create_multiplex();
     let client = Buffer::new(client, 10);
     let mut i = 0;
 
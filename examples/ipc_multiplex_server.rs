@@ -6,10 +6,10 @@ use rand::rngs::SmallRng;
 use rand::{Rng, SeedableRng};
 use tokio_util::sync::CancellationToken;
 use tower::{service_fn, BoxError};
-use tower_rpc::transport::codec::{Codec, CodecStream, SerdeCodec};
+use tower_rpc::transport::codec::{Codec, CodecStream};
 use tower_rpc::transport::ipc::{self, IpcSecurity, OnConflict, SecurityAttributes, ServerId};
 use tower_rpc::transport::Bind;
-use tower_rpc::{make_service_fn, Request, Server, Tagged};
+use tower_rpc::{make_service_fn, multiplex_codec, Request, Server};
 
 #[tokio::main]
 pub async fn main() -> Result<(), BoxError> {
@@ -26,10 +26,7 @@ pub async fn main() -> Result<(), BoxError> {
     .await?;
 
     let server = Server::multiplex(
-        CodecStream::new(
-            transport,
-            SerdeCodec::<Tagged<usize>, Tagged<usize>>::new(Codec::Bincode),
-        ),
+        CodecStream::new(transport, multiplex_codec::<usize, usize>(Codec::Bincode)),
         make_service_fn(|| {
             service_fn(|req: Request<usize>| {
                 Box::pin(async move {
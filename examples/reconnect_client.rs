@@ -1,24 +1,17 @@
 use std::time::Duration;
 
-use tower::reconnect::Reconnect;
-use tower::util::BoxService;
-use tower::{service_fn, BoxError, Service, ServiceExt};
-use tower_rpc::transport::codec::{serde_codec, Codec};
-use tower_rpc::transport::{tcp, Connect};
-use tower_rpc::Client;
+use tower::{BoxError, Service, ServiceExt};
+use tower_rpc::transport::codec::Codec;
+use tower_rpc::transport::tcp;
+use tower_rpc::{pipeline_codec, Backoff, ReconnectingClient};
 
 #[tokio::main]
 pub async fn main() -> Result<(), BoxError> {
-    let make_service = service_fn(move |_: ()| {
-        Box::pin(async move {
-            let socket = tcp::Connection::connect("127.0.0.1:8080".parse()?).await?;
-            let client =
-                Client::new(serde_codec::<usize, usize>(socket, Codec::Bincode)).create_pipeline();
-            Ok::<_, BoxError>(client.boxed())
+    let mut client =
+        ReconnectingClient::<tcp::Connection, _, _>::pipeline("127.0.0.1:8080".parse()?, |conn| {
+            pipeline_codec::<usize, usize>(Codec::Bincode).client(conn)
         })
-    });
-
-    let mut client = Reconnect::new::<BoxService<usize, usize, BoxError>, ()>(make_service, ());
+        .with_backoff(Backoff::default().with_max_delay(Duration::from_secs(5)));
     let mut i = 0;
     loop {
         i = match client.ready().await?.call(i).await {
@@ -2,9 +2,9 @@ use std::process::Stdio;
 use std::time::Duration;
 
 use tower::{BoxError, Service, ServiceExt};
-use tower_rpc::transport::codec::{serde_codec, Codec};
+use tower_rpc::transport::codec::Codec;
 use tower_rpc::transport::stdio::StdioTransport;
-use tower_rpc::Client;
+use tower_rpc::{pipeline_codec, Client};
 
 #[tokio::main]
 pub async fn main() -> Result<(), BoxError> {
@@ -17,7 +17,8 @@ pub async fn main() -> Result<(), BoxError> {
     let client_stream =
         StdioTransport::from_child(&mut process).expect("Process missing io handles");
     let mut client =
-        Client::new(serde_codec::<usize, usize>(client_stream, Codec::Bincode)).create_pipeline();
+        Client::new(pipeline_codec::<usize, usize>(Codec::Bincode).client(client_stream))
+            .create_pipeline();
     let mut i = 0;
 
     loop {
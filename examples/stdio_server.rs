@@ -5,9 +5,9 @@ use std::task::{Context, Poll};
 use background_service::BackgroundServiceManager;
 use tokio_util::sync::CancellationToken;
 use tower::{service_fn, BoxError};
-use tower_rpc::transport::codec::{Codec, CodecStream, SerdeCodec};
+use tower_rpc::transport::codec::{Codec, CodecStream};
 use tower_rpc::transport::stdio::StdioTransport;
-use tower_rpc::{MakeHandler, Request, Server};
+use tower_rpc::{pipeline_codec, MakeHandler, Request, Server};
 
 #[tokio::main]
 pub async fn main() -> Result<(), BoxError> {
@@ -19,7 +19,7 @@ pub async fn main() -> Result<(), BoxError> {
     let transport = StdioTransport::incoming();
 
     let server = Server::pipeline(
-        CodecStream::new(transport, SerdeCodec::<usize, usize>::new(Codec::Bincode)),
+        CodecStream::new(transport, pipeline_codec::<usize, usize>(Codec::Bincode)),
         service_fn(Handler::make),
     );
     let mut context = manager.get_context();
@@ -1,19 +1,16 @@
 use std::time::Duration;
 
 use tower::{BoxError, Service, ServiceExt};
-use tower_rpc::transport::tcp;
-use tower_rpc::Client;
-use transport_async::codec::{serde_codec, Codec};
-use transport_async::Connect;
+use tower_rpc::transport::codec::Codec;
+use tower_rpc::transport::{tcp, Connect};
+use tower_rpc::{pipeline_codec, Client};
 
 #[tokio::main]
 pub async fn main() -> Result<(), BoxError> {
     let client_transport = tcp::Connection::connect("127.0.0.1:8080".parse()?).await?;
-    let mut client = Client::new(serde_codec::<usize, usize>(
-        client_transport,
-        Codec::Bincode,
-    ))
-    .create_pipeline();
+    let mut client =
+        Client::new(pipeline_codec::<usize, usize>(Codec::Bincode).client(client_transport))
+            .create_pipeline();
     let mut i = 0;
 
     loop {
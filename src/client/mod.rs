@@ -1,47 +1,149 @@
-use std::fmt::Debug;
+use std::error::Error;
+use std::io;
 use std::marker::PhantomData;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::Arc;
 
-use futures::{Sink, TryStream};
-use tokio_tower::pipeline;
+use tower::BoxError;
+
+use crate::{Keepalive, RemoteError, RemoteErrorKind};
 
 #[cfg(feature = "multiplex")]
 mod multiplex;
+#[cfg(feature = "multiplex")]
+pub use multiplex::*;
+mod pipeline;
+mod reconnect;
+pub use reconnect::*;
+mod service;
+pub use service::*;
 
 pub struct Client<S, Req, Res> {
     stream: S,
+    keepalive: Keepalive,
     _phantom: PhantomData<(Req, Res)>,
 }
 
-impl<S, Req, Res> Client<S, Req, Res>
-where
-    S: TryStream<Ok = Res> + Sink<Req> + Send + 'static,
-    <S as futures::TryStream>::Error: Debug,
-    <S as futures::Sink<Req>>::Error: Debug,
-    Req: Send + 'static,
-    Res: Send + 'static,
-{
+impl<S, Req, Res> Client<S, Req, Res> {
     pub fn new(stream: S) -> Self {
         Self {
             stream,
+            keepalive: Keepalive::default(),
             _phantom: Default::default(),
         }
     }
 
-    pub fn create_pipeline(self) -> pipeline::client::Client<S, ClientError, Req> {
-        pipeline::Client::new(self.stream)
+    /// Pings the server and closes the connection if it stops answering or sits idle. Calls
+    /// fail with [`ClientError::Unresponsive`] if the server misses too many pings.
+    pub fn with_keepalive(mut self, keepalive: Keepalive) -> Self {
+        self.keepalive = keepalive;
+        self
+    }
+}
+
+// Transport errors are shared so a single connection failure can be reported to every pending
+// call
+#[derive(thiserror::Error, Clone, Debug)]
+pub enum ClientError {
+    #[error("failed to send request")]
+    Send(#[source] Arc<dyn Error + Send + Sync>),
+    #[error("failed to receive response")]
+    Receive(#[source] Arc<dyn Error + Send + Sync>),
+    #[error("failed to decode response")]
+    Decode(#[source] Arc<dyn Error + Send + Sync>),
+    #[error("connection closed")]
+    Closed,
+    #[error("received a response that did not match any pending request")]
+    Desynchronized,
+    #[error("deadline exceeded")]
+    DeadlineExceeded,
+    #[error("remote error")]
+    Remote(#[source] RemoteError),
+    #[error("failed to connect")]
+    Connect(#[source] Arc<dyn Error + Send + Sync>),
+    #[error("streaming calls require a multiplexed connection")]
+    StreamingUnsupported,
+    /// The server is shutting down and won't accept new requests on this connection. The request
+    /// wasn't started, so it can be sent again on a new connection.
+    #[error("server is going away")]
+    GoingAway,
+    /// The server has reached its connection limit and rejected this connection.
+    #[error("server is busy")]
+    Busy,
+    /// The server stopped answering keepalive pings.
+    #[error("server is unresponsive")]
+    Unresponsive,
+}
+
+impl ClientError {
+    /// Whether the error means the underlying connection can no longer be used.
+    pub fn is_connection_error(&self) -> bool {
+        !matches!(
+            self,
+            Self::DeadlineExceeded | Self::Remote(_) | Self::StreamingUnsupported
+        )
+    }
+
+    /// Whether the server turned the request away without running it, so it's safe to send it
+    /// again on a new connection.
+    pub fn is_retryable(&self) -> bool {
+        matches!(self, Self::GoingAway | Self::Busy)
+    }
+
+    pub(crate) fn from_send_error(e: impl Into<BoxError>) -> Self {
+        Self::Send(Arc::from(e.into()))
+    }
+
+    pub(crate) fn from_recv_error(e: impl Into<BoxError>) -> Self {
+        let e = e.into();
+        match e.downcast_ref::<io::Error>() {
+            Some(io_err) if io_err.kind() == io::ErrorKind::InvalidData => {
+                Self::Decode(Arc::from(e))
+            }
+            _ => Self::Receive(Arc::from(e)),
+        }
     }
 }
 
-#[derive(thiserror::Error, Debug)]
-#[error("{0}")]
-pub struct ClientError(String);
-impl<T, I> From<tokio_tower::Error<T, I>> for ClientError
-where
-    T: Sink<I> + TryStream,
-    <T as Sink<I>>::Error: Debug,
-    <T as futures::TryStream>::Error: Debug,
-{
-    fn from(e: tokio_tower::Error<T, I>) -> Self {
-        Self(format!("{e:?}"))
+// Whether the server has told the client to stop sending requests on a connection. Shared between
+// the connection's handles and its driver.
+#[derive(Clone, Default)]
+pub(crate) struct ServerStatus(Arc<AtomicU8>);
+
+impl ServerStatus {
+    const OPEN: u8 = 0;
+    const GOING_AWAY: u8 = 1;
+    const BUSY: u8 = 2;
+
+    pub(crate) fn set_going_away(&self) {
+        self.0.store(Self::GOING_AWAY, Ordering::SeqCst);
+    }
+
+    pub(crate) fn set_busy(&self) {
+        self.0.store(Self::BUSY, Ordering::SeqCst);
+    }
+
+    /// The error new requests should fail with, if the server won't accept them.
+    pub(crate) fn error(&self) -> Option<ClientError> {
+        match self.0.load(Ordering::SeqCst) {
+            Self::OPEN => None,
+            Self::GOING_AWAY => Some(ClientError::GoingAway),
+            _ => Some(ClientError::Busy),
+        }
+    }
+
+    pub(crate) fn closed_error(&self) -> ClientError {
+        self.error().unwrap_or(ClientError::Closed)
+    }
+}
+
+impl From<RemoteError> for ClientError {
+    fn from(e: RemoteError) -> Self {
+        match e.kind() {
+            RemoteErrorKind::DeadlineExceeded => Self::DeadlineExceeded,
+            // Only sent for requests the server didn't start because it's shutting down
+            RemoteErrorKind::Unavailable => Self::GoingAway,
+            _ => Self::Remote(e),
+        }
     }
 }
@@ -1,64 +1,868 @@
-use std::fmt::Debug;
-use std::marker::PhantomData;
+use std::collections::{HashMap, VecDeque};
+use std::future::{self, Future};
+use std::mem;
 use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Context, Poll};
 
-use futures::{Sink, TryStream};
-use tokio_tower::multiplex::{self, MultiplexTransport, TagStore};
-use tower::util::BoxService;
-use tower::{ServiceBuilder, ServiceExt};
+use futures::stream::BoxStream;
+use futures::{Sink, Stream, StreamExt, TryStream};
+use tokio::sync::{mpsc, oneshot};
+use tower::{BoxError, ServiceExt};
+use tracing::{debug, warn};
 
-use crate::service::DemultiplexService;
-use crate::{Client, ClientError, Tagged};
+use crate::{
+    CastSender, Client, ClientError, ClientFrame, ClientFuture, ClientMessage, ClientService,
+    ClientStream, KeepaliveEvent, KeepaliveTimer, RequestFrame, ResponseFrame, ServerFrame,
+    ServerMessage, ServerStatus, Tag, Tagged, STREAM_WINDOW,
+};
 
-#[cfg(feature = "multiplex")]
 impl<S, Req, Res> Client<S, Tagged<Req>, Tagged<Res>>
 where
-    S: TryStream<Ok = Tagged<Res>> + Sink<Tagged<Req>> + Send + 'static,
-    <S as futures::TryStream>::Error: Debug,
-    <S as futures::Sink<Tagged<Req>>>::Error: Debug,
-    Req: Unpin + Send + 'static,
-    Res: Unpin + Send + Sync + Debug + 'static,
+    S: TryStream<Ok = ServerMessage<Res>> + Sink<ClientMessage<Req>> + Send + 'static,
+    <S as futures::TryStream>::Error: Into<BoxError>,
+    <S as futures::Sink<ClientMessage<Req>>>::Error: Into<BoxError>,
+    Req: Send + 'static,
+    Res: Send + 'static,
 {
-    pub fn create_multiplex(self) -> BoxService<Req, Res, ClientError> {
-        let client =
-            multiplex::Client::builder(MultiplexTransport::new(self.stream, SlabStore::default()))
-                .build();
+    pub fn create_multiplex(self) -> ClientService<Req, Res> {
+        self.spawn_multiplex(None)
+    }
 
-        ServiceBuilder::default()
-            .layer_fn(DemultiplexService::new)
-            .service(client)
-            .boxed()
+    /// Creates a multiplexed client along with a stream of the notifications the server sends
+    /// outside of any request.
+    pub fn create_multiplex_with_notifications(
+        self,
+    ) -> (ClientService<Req, Res>, Notifications<Res>) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        (self.spawn_multiplex(Some(tx)), Notifications { rx })
     }
+
+    fn spawn_multiplex(
+        self,
+        notifications: Option<mpsc::UnboundedSender<Res>>,
+    ) -> ClientService<Req, Res> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let tags = Arc::new(Mutex::new(SlabStore::default()));
+        let status = ServerStatus::default();
+        tokio::spawn(MultiplexDriver {
+            transport: Box::pin(self.stream),
+            rx,
+            rx_closed: false,
+            tags: tags.clone(),
+            pending: Default::default(),
+            uploads: Default::default(),
+            resume_uploads: false,
+            cancelled: Vec::new(),
+            control: VecDeque::new(),
+            unflushed: Vec::new(),
+            closing: false,
+            keepalive: KeepaliveTimer::new(self.keepalive),
+            notifications,
+            status: status.clone(),
+        });
+
+        let client = MultiplexClient { tx, tags, status };
+        let caster = client.clone();
+        ClientService::new(
+            client.clone().boxed(),
+            Box::new(move |frame| caster.cast(frame)),
+        )
+        .with_multiplex(client)
+    }
+}
+
+pub struct Notifications<T> {
+    rx: mpsc::UnboundedReceiver<T>,
 }
 
-pub(crate) struct SlabStore<Req, Res> {
-    slab: slab::Slab<()>,
-    _phantom: PhantomData<(Req, Res)>,
+impl<T> Stream for Notifications<T> {
+    type Item = T;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.rx.poll_recv(cx)
+    }
 }
 
-impl<Req, Res> Default for SlabStore<Req, Res> {
-    fn default() -> Self {
+type ResponseSender<Res> = oneshot::Sender<Result<ResponseFrame<Res>, ClientError>>;
+type StreamSender<Res> = mpsc::Sender<Result<ResponseFrame<Res>, ClientError>>;
+
+// Room for a full window of items plus the frame or error that ends the stream
+const STREAM_CAPACITY: usize = STREAM_WINDOW as usize + 1;
+
+enum Pending<Res> {
+    Unary(ResponseSender<Res>),
+    Stream(StreamSender<Res>),
+}
+
+impl<Res> Pending<Res> {
+    fn send(self, res: Result<ResponseFrame<Res>, ClientError>) {
+        match self {
+            Self::Unary(tx) => {
+                let _ = tx.send(res);
+            }
+            Self::Stream(tx) => {
+                let _ = tx.try_send(res);
+            }
+        }
+    }
+}
+
+enum Message<Req, Res> {
+    Call {
+        tag: Tag,
+        frame: ClientFrame<Req>,
+        upload: Option<BoxStream<'static, Req>>,
+        pending: Pending<Res>,
+    },
+    Cancel(Tag),
+    // The caller consumed this many items of a streaming response
+    Credit(Tag, u32),
+    Cast {
+        frame: RequestFrame<Req>,
+        tx: CastSender,
+    },
+}
+
+pub(crate) struct MultiplexClient<Req, Res> {
+    tx: mpsc::UnboundedSender<Message<Req, Res>>,
+    tags: Arc<Mutex<SlabStore>>,
+    status: ServerStatus,
+}
+
+impl<Req, Res> Clone for MultiplexClient<Req, Res> {
+    fn clone(&self) -> Self {
         Self {
-            slab: Default::default(),
-            _phantom: Default::default(),
+            tx: self.tx.clone(),
+            tags: self.tags.clone(),
+            status: self.status.clone(),
+        }
+    }
+}
+
+impl<Req, Res> MultiplexClient<Req, Res>
+where
+    Req: Send + 'static,
+    Res: Send + 'static,
+{
+    fn send_call(
+        &self,
+        frame: ClientFrame<Req>,
+        upload: Option<BoxStream<'static, Req>>,
+        pending: Pending<Res>,
+    ) -> Option<CancelGuard<Req, Res>> {
+        if self.status.error().is_some() {
+            return None;
+        }
+        let tag = self.tags.lock().expect("tag store poisoned").assign_tag();
+        if self
+            .tx
+            .send(Message::Call {
+                tag,
+                frame,
+                upload,
+                pending,
+            })
+            .is_err()
+        {
+            self.tags
+                .lock()
+                .expect("tag store poisoned")
+                .finish_tag(tag);
+            return None;
+        }
+        Some(CancelGuard {
+            tag,
+            tx: self.tx.clone(),
+            completed: false,
+        })
+    }
+
+    fn unary(
+        &self,
+        frame: ClientFrame<Req>,
+        upload: Option<BoxStream<'static, Req>>,
+    ) -> ClientFuture<ResponseFrame<Res>> {
+        let (tx, rx) = oneshot::channel();
+        let Some(mut guard) = self.send_call(frame, upload, Pending::Unary(tx)) else {
+            return Box::pin(future::ready(Err(self.status.closed_error())));
+        };
+        Box::pin(async move {
+            let res = rx.await;
+            guard.completed = true;
+            res.unwrap_or(Err(ClientError::Closed))
+        })
+    }
+
+    fn streaming(
+        &self,
+        frame: ClientFrame<Req>,
+        upload: Option<BoxStream<'static, Req>>,
+    ) -> ClientStream<Res> {
+        let (tx, rx) = mpsc::channel(STREAM_CAPACITY);
+        match self.send_call(frame, upload, Pending::Stream(tx)) {
+            Some(guard) => Box::pin(StreamingCall {
+                rx,
+                guard,
+                consumed: 0,
+            }),
+            None => Box::pin(futures::stream::once(future::ready(Err(self
+                .status
+                .closed_error())))),
+        }
+    }
+
+    fn cast(&self, frame: RequestFrame<Req>) -> ClientFuture<()> {
+        if let Some(e) = self.status.error() {
+            return Box::pin(future::ready(Err(e)));
+        }
+        let (tx, rx) = oneshot::channel();
+        if self.tx.send(Message::Cast { frame, tx }).is_err() {
+            return Box::pin(future::ready(Err(self.status.closed_error())));
+        }
+        Box::pin(async move { rx.await.unwrap_or(Err(ClientError::Closed)) })
+    }
+
+    pub(crate) fn call_streaming(&self, frame: RequestFrame<Req>) -> ClientStream<Res> {
+        self.streaming(ClientFrame::Request(frame), None)
+    }
+
+    pub(crate) fn call_client_streaming<S>(
+        &self,
+        frame: RequestFrame<()>,
+        requests: S,
+    ) -> ClientFuture<Res>
+    where
+        S: Stream<Item = Req> + Send + 'static,
+    {
+        let res = self.unary(ClientFrame::Open(frame), Some(requests.boxed()));
+        Box::pin(async move { Ok(res.await?.into_result()?) })
+    }
+
+    pub(crate) fn call_bidirectional<S>(
+        &self,
+        frame: RequestFrame<()>,
+        requests: S,
+    ) -> ClientStream<Res>
+    where
+        S: Stream<Item = Req> + Send + 'static,
+    {
+        self.streaming(ClientFrame::Open(frame), Some(requests.boxed()))
+    }
+}
+
+impl<Req, Res> tower::Service<RequestFrame<Req>> for MultiplexClient<Req, Res>
+where
+    Req: Send + 'static,
+    Res: Send + 'static,
+{
+    type Response = ResponseFrame<Res>;
+    type Error = ClientError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        if self.status.error().is_some() || self.tx.is_closed() {
+            Poll::Ready(Err(self.status.closed_error()))
+        } else {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    fn call(&mut self, frame: RequestFrame<Req>) -> Self::Future {
+        self.unary(ClientFrame::Request(frame), None)
+    }
+}
+
+struct StreamingCall<Req, Res> {
+    rx: mpsc::Receiver<Result<ResponseFrame<Res>, ClientError>>,
+    guard: CancelGuard<Req, Res>,
+    // Items received since credit was last sent back to the server
+    consumed: u32,
+}
+
+impl<Req, Res> Stream for StreamingCall<Req, Res> {
+    type Item = Result<Res, ClientError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        match self.rx.poll_recv(cx) {
+            Poll::Ready(Some(res)) => {
+                self.consumed += 1;
+                // Credit is sent back in batches to keep the number of frames down
+                if self.consumed >= STREAM_WINDOW / 2 {
+                    let _ = self
+                        .guard
+                        .tx
+                        .send(Message::Credit(self.guard.tag, self.consumed));
+                    self.consumed = 0;
+                }
+                Poll::Ready(Some(
+                    res.and_then(|frame| frame.into_result().map_err(Into::into)),
+                ))
+            }
+            Poll::Ready(None) => {
+                self.guard.completed = true;
+                Poll::Ready(None)
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+// Notifies the driver when a response future or stream is dropped before the response finishes
+struct CancelGuard<Req, Res> {
+    tag: Tag,
+    tx: mpsc::UnboundedSender<Message<Req, Res>>,
+    completed: bool,
+}
+
+impl<Req, Res> Drop for CancelGuard<Req, Res> {
+    fn drop(&mut self) {
+        if !self.completed {
+            let _ = self.tx.send(Message::Cancel(self.tag));
+        }
+    }
+}
+
+// A request stream that hasn't been fully sent yet
+struct Upload<Req> {
+    stream: BoxStream<'static, Req>,
+    // Items the server has room for
+    credit: u32,
+}
+
+struct MultiplexDriver<S, Req, Res> {
+    transport: Pin<Box<S>>,
+    rx: mpsc::UnboundedReceiver<Message<Req, Res>>,
+    rx_closed: bool,
+    tags: Arc<Mutex<SlabStore>>,
+    pending: HashMap<Tag, Pending<Res>>,
+    uploads: HashMap<Tag, Upload<Req>>,
+    // Set when the server grants credit so uploads that ran out are polled again
+    resume_uploads: bool,
+    // Tags the driver gave up on itself that the server still needs to be told about
+    cancelled: Vec<Tag>,
+    // Pings and pongs, which are sent ahead of everything else
+    control: VecDeque<ClientMessage<Req>>,
+    // Casts that were written to the transport but haven't been flushed yet
+    unflushed: Vec<CastSender>,
+    closing: bool,
+    keepalive: Option<KeepaliveTimer>,
+    notifications: Option<mpsc::UnboundedSender<Res>>,
+    // Set once the server sends a GOAWAY. Calls that were already sent keep running, but new ones
+    // are rejected. Cancellations still need to reach the server.
+    status: ServerStatus,
+}
+
+impl<S, Req, Res> MultiplexDriver<S, Req, Res>
+where
+    S: TryStream<Ok = ServerMessage<Res>> + Sink<ClientMessage<Req>>,
+    <S as futures::TryStream>::Error: Into<BoxError>,
+    <S as futures::Sink<ClientMessage<Req>>>::Error: Into<BoxError>,
+{
+    fn finish_tag(&self, tag: Tag) -> bool {
+        self.tags
+            .lock()
+            .expect("tag store poisoned")
+            .finish_tag(tag)
+    }
+
+    fn cancel_tag(&self, tag: Tag) {
+        self.tags
+            .lock()
+            .expect("tag store poisoned")
+            .cancel_tag(tag)
+    }
+
+    fn remove_call(&mut self, tag: Tag) -> Option<Pending<Res>> {
+        self.uploads.remove(&tag);
+        self.pending.remove(&tag)
+    }
+
+    fn handle_frame(&mut self, tag: Tag, frame: ServerFrame<Res>) {
+        match frame {
+            ServerFrame::Item(response) => match self.pending.get(&tag) {
+                // The last slot is kept for the frame that ends the stream
+                Some(Pending::Stream(tx)) if tx.capacity() > 1 => {
+                    let _ = tx.try_send(Ok(response));
+                }
+                Some(Pending::Stream(_)) => {
+                    debug!("Server sent more stream items than allowed for {tag:?}");
+                    if let Some(pending) = self.remove_call(tag) {
+                        self.cancel_tag(tag);
+                        self.cancelled.push(tag);
+                        pending.send(Err(ClientError::Desynchronized));
+                    }
+                }
+                Some(Pending::Unary(_)) => {
+                    // The server treated a unary call as a streaming one so the caller can't make
+                    // sense of the response
+                    debug!("Received a stream item for unary call {tag:?}");
+                    if let Some(pending) = self.remove_call(tag) {
+                        self.cancel_tag(tag);
+                        self.cancelled.push(tag);
+                        pending.send(Err(ClientError::Desynchronized));
+                    }
+                }
+                None => debug!("Dropping stream item for unknown tag {tag:?}"),
+            },
+            // A streaming call can also end with a single response if it fails before the stream
+            // starts
+            ServerFrame::Response(response) => {
+                if self.finish_tag(tag) {
+                    if let Some(pending) = self.remove_call(tag) {
+                        pending.send(Ok(response));
+                    }
+                }
+            }
+            ServerFrame::End => {
+                // Dropping the sender ends the stream
+                if self.finish_tag(tag) {
+                    self.remove_call(tag);
+                }
+            }
+            ServerFrame::Credit(n) => {
+                if let Some(upload) = self.uploads.get_mut(&tag) {
+                    upload.credit = upload.credit.saturating_add(n);
+                    self.resume_uploads = true;
+                }
+            }
+        }
+    }
+
+    fn is_going_away(&self) -> bool {
+        self.status.error().is_some()
+    }
+
+    // Fails a call or cast that was queued but never sent
+    fn reject(&self, message: Message<Req, Res>, error: &ClientError) {
+        match message {
+            Message::Call { tag, pending, .. } => {
+                self.finish_tag(tag);
+                pending.send(Err(error.clone()));
+            }
+            Message::Cast { tx, .. } => {
+                let _ = tx.send(Err(error.clone()));
+            }
+            Message::Cancel(_) | Message::Credit(..) => {}
+        }
+    }
+
+    fn reject_queued(&mut self, error: &ClientError) {
+        self.rx.close();
+        while let Ok(message) = self.rx.try_recv() {
+            self.reject(message, error);
+        }
+    }
+
+    fn fail(&mut self, error: ClientError) -> Poll<()> {
+        debug!("Multiplex client failed: {error:?}");
+        for (tag, pending) in self.pending.drain() {
+            self.tags
+                .lock()
+                .expect("tag store poisoned")
+                .finish_tag(tag);
+            pending.send(Err(error.clone()));
+        }
+        self.uploads.clear();
+        for tx in self.unflushed.drain(..) {
+            let _ = tx.send(Err(error.clone()));
+        }
+        self.reject_queued(&error);
+        Poll::Ready(())
+    }
+
+    fn start_send(&mut self, frame: Tagged<ClientFrame<Req>>) -> Result<(), ClientError> {
+        self.transport
+            .as_mut()
+            .start_send(ClientMessage::Call(frame))
+            .map_err(ClientError::from_send_error)
+    }
+
+    fn poll_send(&mut self, cx: &mut Context<'_>) -> Result<(), ClientError> {
+        while let Some(message) = self.control.pop_front() {
+            match self.transport.as_mut().poll_ready(cx) {
+                Poll::Ready(Ok(())) => {}
+                Poll::Ready(Err(e)) => return Err(ClientError::from_send_error(e)),
+                Poll::Pending => {
+                    self.control.push_front(message);
+                    return Ok(());
+                }
+            }
+            self.transport
+                .as_mut()
+                .start_send(message)
+                .map_err(ClientError::from_send_error)?;
+        }
+        while !self.cancelled.is_empty() {
+            match self.transport.as_mut().poll_ready(cx) {
+                Poll::Ready(Ok(())) => {}
+                Poll::Ready(Err(e)) => return Err(ClientError::from_send_error(e)),
+                Poll::Pending => return Ok(()),
+            }
+            let tag = self.cancelled.pop().expect("cancelled tag missing");
+            self.start_send(Tagged {
+                tag,
+                value: ClientFrame::Cancel,
+            })?;
+        }
+        while !self.rx_closed {
+            match self.transport.as_mut().poll_ready(cx) {
+                Poll::Ready(Ok(())) => {}
+                Poll::Ready(Err(e)) => return Err(ClientError::from_send_error(e)),
+                Poll::Pending => break,
+            }
+            match self.rx.poll_recv(cx) {
+                Poll::Ready(Some(message @ (Message::Call { .. } | Message::Cast { .. })))
+                    if self.is_going_away() =>
+                {
+                    self.reject(message, &ClientError::GoingAway);
+                }
+                Poll::Ready(Some(Message::Call {
+                    tag,
+                    frame,
+                    upload,
+                    pending,
+                })) => {
+                    self.pending.insert(tag, pending);
+                    if let Some(stream) = upload {
+                        self.uploads.insert(
+                            tag,
+                            Upload {
+                                stream,
+                                credit: STREAM_WINDOW,
+                            },
+                        );
+                    }
+                    self.start_send(Tagged { tag, value: frame })?;
+                }
+                Poll::Ready(Some(Message::Cancel(tag))) => {
+                    // The response may have arrived before the caller dropped its future, in
+                    // which case the tag was already finished
+                    if self.remove_call(tag).is_some() {
+                        self.cancel_tag(tag);
+                        self.start_send(Tagged {
+                            tag,
+                            value: ClientFrame::Cancel,
+                        })?;
+                    }
+                }
+                Poll::Ready(Some(Message::Credit(tag, n))) => {
+                    if self.pending.contains_key(&tag) {
+                        self.start_send(Tagged {
+                            tag,
+                            value: ClientFrame::Credit(n),
+                        })?;
+                    }
+                }
+                Poll::Ready(Some(Message::Cast { frame, tx })) => {
+                    // The cast only counts as sent once it's been flushed
+                    let res = self
+                        .transport
+                        .as_mut()
+                        .start_send(ClientMessage::Cast(frame))
+                        .map_err(ClientError::from_send_error);
+                    match res {
+                        Ok(()) => self.unflushed.push(tx),
+                        Err(e) => {
+                            let _ = tx.send(Err(e.clone()));
+                            return Err(e);
+                        }
+                    }
+                }
+                Poll::Ready(None) => self.rx_closed = true,
+                Poll::Pending => break,
+            }
+        }
+        self.poll_uploads(cx)?;
+        match self.transport.as_mut().poll_flush(cx) {
+            Poll::Ready(Ok(())) => {
+                for tx in self.unflushed.drain(..) {
+                    let _ = tx.send(Ok(()));
+                }
+            }
+            Poll::Ready(Err(e)) => return Err(ClientError::from_send_error(e)),
+            Poll::Pending => {}
+        }
+        Ok(())
+    }
+
+    // Flushes anything that's left and closes the transport
+    fn poll_close(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        let res =
+            ready!(self.transport.as_mut().poll_close(cx)).map_err(ClientError::from_send_error);
+        if let Err(e) = &res {
+            debug!("Failed to close the connection: {e:?}");
+        }
+        for tx in self.unflushed.drain(..) {
+            let _ = tx.send(res.clone());
+        }
+        Poll::Ready(())
+    }
+
+    fn poll_uploads(&mut self, cx: &mut Context<'_>) -> Result<(), ClientError> {
+        // Items are taken from each upload in turn so one large upload can't hold up the others
+        loop {
+            let mut progress = false;
+            let mut blocked = false;
+            let mut finished = Vec::new();
+            for (tag, upload) in self.uploads.iter_mut() {
+                match self.transport.as_mut().poll_ready(cx) {
+                    Poll::Ready(Ok(())) => {}
+                    Poll::Ready(Err(e)) => return Err(ClientError::from_send_error(e)),
+                    Poll::Pending => {
+                        blocked = true;
+                        break;
+                    }
+                }
+                // Wait for the server to grant more credit
+                if upload.credit == 0 {
+                    continue;
+                }
+                let value = match upload.stream.poll_next_unpin(cx) {
+                    Poll::Ready(Some(item)) => {
+                        upload.credit -= 1;
+                        ClientFrame::Item(item)
+                    }
+                    Poll::Ready(None) => {
+                        finished.push(*tag);
+                        ClientFrame::End
+                    }
+                    Poll::Pending => continue,
+                };
+                self.transport
+                    .as_mut()
+                    .start_send(ClientMessage::Call(Tagged { tag: *tag, value }))
+                    .map_err(ClientError::from_send_error)?;
+                progress = true;
+            }
+            for tag in finished {
+                self.uploads.remove(&tag);
+            }
+            if blocked || !progress {
+                return Ok(());
+            }
+        }
+    }
+
+    fn poll_recv(&mut self, cx: &mut Context<'_>) -> Poll<ClientError> {
+        loop {
+            let message = match self.transport.as_mut().try_poll_next(cx) {
+                Poll::Ready(Some(Ok(message))) => message,
+                Poll::Ready(Some(Err(e))) => {
+                    return Poll::Ready(ClientError::from_recv_error(e));
+                }
+                Poll::Ready(None) => return Poll::Ready(ClientError::Closed),
+                Poll::Pending => return Poll::Pending,
+            };
+            if let Some(keepalive) = &mut self.keepalive {
+                keepalive.received();
+            }
+            match message {
+                ServerMessage::Call(Tagged { tag, value }) => self.handle_frame(tag, value),
+                ServerMessage::Notification(notification) => match &self.notifications {
+                    Some(tx) if tx.send(notification).is_ok() => {}
+                    _ => debug!("Dropping notification with no receiver"),
+                },
+                ServerMessage::GoAway => {
+                    debug!("Server is going away");
+                    self.status.set_going_away();
+                }
+                ServerMessage::Busy => {
+                    self.status.set_busy();
+                    return Poll::Ready(ClientError::Busy);
+                }
+                ServerMessage::Ping => self.control.push_back(ClientMessage::Pong),
+                ServerMessage::Pong => {}
+            }
         }
     }
+
+    fn poll_keepalive(&mut self, cx: &mut Context<'_>) -> Result<(), ClientError> {
+        let Some(keepalive) = &mut self.keepalive else {
+            return Ok(());
+        };
+        let idle = self.pending.is_empty();
+        while let Poll::Ready(event) = keepalive.poll(cx, idle) {
+            match event {
+                KeepaliveEvent::Ping => self.control.push_back(ClientMessage::Ping),
+                KeepaliveEvent::Unresponsive => return Err(ClientError::Unresponsive),
+                KeepaliveEvent::Idle => {
+                    debug!("Closing idle connection");
+                    return Err(ClientError::Closed);
+                }
+            }
+        }
+        Ok(())
+    }
 }
 
-impl<Req, Res> TagStore<Tagged<Req>, Tagged<Res>> for SlabStore<Req, Res>
+impl<S, Req, Res> Future for MultiplexDriver<S, Req, Res>
 where
-    Req: Unpin,
-    Res: Unpin,
+    S: TryStream<Ok = ServerMessage<Res>> + Sink<ClientMessage<Req>>,
+    <S as futures::TryStream>::Error: Into<BoxError>,
+    <S as futures::Sink<ClientMessage<Req>>>::Error: Into<BoxError>,
 {
-    type Tag = usize;
-    fn assign_tag(mut self: Pin<&mut Self>, request: &mut Tagged<Req>) -> usize {
-        let tag = self.slab.insert(());
-        request.tag = tag;
-        tag
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        if this.closing {
+            return this.poll_close(cx);
+        }
+        if let Err(e) = this.poll_send(cx) {
+            return this.fail(e);
+        }
+        if let Poll::Ready(e) = this.poll_recv(cx) {
+            return this.fail(e);
+        }
+        if let Err(e) = this.poll_keepalive(cx) {
+            return this.fail(e);
+        }
+        // Handling responses may have queued up cancellations or pongs, or granted credit for
+        // uploads
+        if !this.cancelled.is_empty()
+            || !this.control.is_empty()
+            || mem::take(&mut this.resume_uploads)
+        {
+            if let Err(e) = this.poll_send(cx) {
+                return this.fail(e);
+            }
+        }
+        if (this.rx_closed || this.is_going_away()) && this.pending.is_empty() {
+            // Every handle is gone or the server is going away, so nothing else can be sent
+            let error = this.status.closed_error();
+            this.reject_queued(&error);
+            this.closing = true;
+            return this.poll_close(cx);
+        }
+        Poll::Pending
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct SlabStore {
+    // Generation of the request currently using each slot
+    slab: slab::Slab<u32>,
+    // Generation of the last cancelled request for each slot. The server may still answer it, which
+    // is expected and shouldn't be reported as a stale tag.
+    cancelled: HashMap<u32, u32>,
+    next_generation: u32,
+}
+
+impl SlabStore {
+    fn assign_tag(&mut self) -> Tag {
+        let generation = self.next_generation;
+        self.next_generation = self.next_generation.wrapping_add(1);
+        let index = self.slab.insert(generation);
+        Tag {
+            index: u32::try_from(index).expect("too many requests in flight"),
+            generation,
+        }
+    }
+
+    fn finish_tag(&mut self, tag: Tag) -> bool {
+        let index = tag.index as usize;
+        if self.slab.get(index) == Some(&tag.generation) {
+            self.slab.remove(index);
+            true
+        } else if self.cancelled.get(&tag.index) == Some(&tag.generation) {
+            debug!("Dropping response for cancelled tag {tag:?}");
+            self.cancelled.remove(&tag.index);
+            false
+        } else {
+            warn!(
+                "Rejecting response for stale tag {tag:?}, current generation is {:?}",
+                self.slab.get(index)
+            );
+            false
+        }
+    }
+
+    fn cancel_tag(&mut self, tag: Tag) {
+        if self.finish_tag(tag) {
+            self.cancelled.insert(tag.index, tag.generation);
+        }
+    }
+}
+
+#[cfg(all(test, feature = "local"))]
+mod tests {
+    use std::time::Duration;
+
+    use futures::{SinkExt, TryStreamExt};
+    use tower::Service;
+    use transport_async::local;
+
+    use super::*;
+
+    async fn recv_frame<S>(server: &mut S) -> (Tag, ClientFrame<u64>)
+    where
+        S: TryStream<Ok = ClientMessage<u64>> + Unpin,
+        S::Error: std::fmt::Debug,
+    {
+        match server.try_next().await.unwrap() {
+            Some(ClientMessage::Call(Tagged { tag, value })) => (tag, value),
+            Some(_) => panic!("expected a call"),
+            None => panic!("connection closed"),
+        }
     }
 
-    fn finish_tag(mut self: Pin<&mut Self>, response: &Tagged<Res>) -> usize {
-        self.slab.remove(response.tag);
-        response.tag
+    #[tokio::test]
+    async fn responses_for_a_reused_slot_are_matched_by_generation() {
+        let (transport, client_stream) = local::unbounded_channel();
+        let mut transport = Box::pin(transport);
+        let mut client = Client::new(client_stream.connect_unbounded().unwrap()).create_multiplex();
+        let mut server = Box::pin(transport.try_next().await.unwrap().unwrap());
+
+        // Cancelling the first call frees its slot for the second one
+        let first = client.ready().await.unwrap().call(1u64);
+        let (stale, _) = recv_frame(&mut server).await;
+        drop(first);
+        assert!(matches!(
+            recv_frame(&mut server).await,
+            (tag, ClientFrame::Cancel) if tag == stale
+        ));
+        let second = client.ready().await.unwrap().call(2);
+        let (current, _) = recv_frame(&mut server).await;
+        assert_eq!(current.index, stale.index);
+        assert_ne!(current.generation, stale.generation);
+
+        // A late response to the cancelled call must not complete the new one
+        for (tag, value) in [(stale, 1), (current, 2)] {
+            server
+                .send(ServerMessage::Call(Tagged {
+                    tag,
+                    value: ServerFrame::Response(ResponseFrame::ok(value)),
+                }))
+                .await
+                .unwrap();
+        }
+        let res = tokio::time::timeout(Duration::from_secs(5), second)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(res, 2);
+    }
+
+    #[tokio::test]
+    async fn casts_are_sent_without_taking_a_tag() {
+        let (transport, client_stream) = local::unbounded_channel();
+        let mut transport = Box::pin(transport);
+        let mut client = Client::new(client_stream.connect_unbounded().unwrap()).create_multiplex();
+        let mut server = Box::pin(transport.try_next().await.unwrap().unwrap());
+
+        tokio::time::timeout(Duration::from_secs(5), client.cast(1u64))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(matches!(
+            server.try_next().await.unwrap(),
+            Some(ClientMessage::Cast(RequestFrame { value: 1, .. }))
+        ));
+
+        // The next call gets the same tag a fresh connection would, so the cast never held a slot
+        let _call = client.ready().await.unwrap().call(2);
+        let (tag, _) = recv_frame(&mut server).await;
+        assert_eq!(tag, SlabStore::default().assign_tag());
     }
 }
@@ -0,0 +1,327 @@
+use std::collections::VecDeque;
+use std::future::{self, Future};
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+use futures::{Sink, TryStream};
+use tokio::sync::{mpsc, oneshot};
+use tower::{BoxError, ServiceExt};
+use tracing::debug;
+
+use crate::{
+    CastSender, Client, ClientError, ClientFuture, ClientService, KeepaliveEvent, KeepaliveTimer,
+    PipelineClientMessage, PipelineServerMessage, RequestFrame, ResponseFrame, ServerStatus,
+};
+
+impl<S, Req, Res> Client<S, Req, Res>
+where
+    S: TryStream<Ok = PipelineServerMessage<Res>>
+        + Sink<PipelineClientMessage<Req>>
+        + Send
+        + 'static,
+    <S as futures::TryStream>::Error: Into<BoxError>,
+    <S as futures::Sink<PipelineClientMessage<Req>>>::Error: Into<BoxError>,
+    Req: Send + 'static,
+    Res: Send + 'static,
+{
+    pub fn create_pipeline(self) -> ClientService<Req, Res> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let status = ServerStatus::default();
+        tokio::spawn(PipelineDriver {
+            transport: Box::pin(self.stream),
+            rx,
+            rx_closed: false,
+            pending: VecDeque::new(),
+            control: VecDeque::new(),
+            unflushed: Vec::new(),
+            closing: false,
+            keepalive: KeepaliveTimer::new(self.keepalive),
+            status: status.clone(),
+        });
+
+        let client = PipelineClient { tx, status };
+        let caster = client.clone();
+        ClientService::new(client.boxed(), Box::new(move |frame| caster.cast(frame)))
+    }
+}
+
+type ResponseSender<Res> = oneshot::Sender<Result<ResponseFrame<Res>, ClientError>>;
+
+enum Message<Req, Res> {
+    Call {
+        frame: RequestFrame<Req>,
+        tx: ResponseSender<Res>,
+    },
+    Cast {
+        frame: RequestFrame<Req>,
+        tx: CastSender,
+    },
+}
+
+struct PipelineClient<Req, Res> {
+    tx: mpsc::UnboundedSender<Message<Req, Res>>,
+    status: ServerStatus,
+}
+
+impl<Req, Res> Clone for PipelineClient<Req, Res> {
+    fn clone(&self) -> Self {
+        Self {
+            tx: self.tx.clone(),
+            status: self.status.clone(),
+        }
+    }
+}
+
+impl<Req, Res> PipelineClient<Req, Res>
+where
+    Req: Send + 'static,
+    Res: Send + 'static,
+{
+    fn cast(&self, frame: RequestFrame<Req>) -> ClientFuture<()> {
+        if let Some(e) = self.status.error() {
+            return Box::pin(future::ready(Err(e)));
+        }
+        let (tx, rx) = oneshot::channel();
+        if self.tx.send(Message::Cast { frame, tx }).is_err() {
+            return Box::pin(future::ready(Err(self.status.closed_error())));
+        }
+        Box::pin(async move { rx.await.unwrap_or(Err(ClientError::Closed)) })
+    }
+}
+
+impl<Req, Res> tower::Service<RequestFrame<Req>> for PipelineClient<Req, Res>
+where
+    Req: Send + 'static,
+    Res: Send + 'static,
+{
+    type Response = ResponseFrame<Res>;
+    type Error = ClientError;
+    type Future = ClientFuture<ResponseFrame<Res>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        if self.status.error().is_some() || self.tx.is_closed() {
+            Poll::Ready(Err(self.status.closed_error()))
+        } else {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    fn call(&mut self, frame: RequestFrame<Req>) -> Self::Future {
+        if let Some(e) = self.status.error() {
+            return Box::pin(future::ready(Err(e)));
+        }
+        let (tx, rx) = oneshot::channel();
+        if self.tx.send(Message::Call { frame, tx }).is_err() {
+            return Box::pin(future::ready(Err(self.status.closed_error())));
+        }
+        Box::pin(async move { rx.await.unwrap_or(Err(ClientError::Closed)) })
+    }
+}
+
+struct PipelineDriver<S, Req, Res> {
+    transport: Pin<Box<S>>,
+    rx: mpsc::UnboundedReceiver<Message<Req, Res>>,
+    rx_closed: bool,
+    // Responses arrive in the same order the requests were sent
+    pending: VecDeque<ResponseSender<Res>>,
+    // Pings and pongs, which are sent ahead of any queued requests
+    control: VecDeque<PipelineClientMessage<Req>>,
+    // Casts that were written to the transport but haven't been flushed yet
+    unflushed: Vec<CastSender>,
+    closing: bool,
+    keepalive: Option<KeepaliveTimer>,
+    // Set once the server sends a GOAWAY. Requests that were already sent still get responses, but
+    // nothing new is sent.
+    status: ServerStatus,
+}
+
+impl<S, Req, Res> PipelineDriver<S, Req, Res>
+where
+    S: TryStream<Ok = PipelineServerMessage<Res>> + Sink<PipelineClientMessage<Req>>,
+    <S as futures::TryStream>::Error: Into<BoxError>,
+    <S as futures::Sink<PipelineClientMessage<Req>>>::Error: Into<BoxError>,
+{
+    // Fails requests that were queued but never sent
+    fn reject_queued(&mut self, error: &ClientError) {
+        self.rx.close();
+        while let Ok(message) = self.rx.try_recv() {
+            match message {
+                Message::Call { tx, .. } => {
+                    let _ = tx.send(Err(error.clone()));
+                }
+                Message::Cast { tx, .. } => {
+                    let _ = tx.send(Err(error.clone()));
+                }
+            }
+        }
+        self.rx_closed = true;
+    }
+
+    fn go_away(&mut self) {
+        debug!("Server is going away");
+        // Set before closing the channel so handles report the right error when sending fails
+        self.status.set_going_away();
+        self.reject_queued(&ClientError::GoingAway);
+    }
+
+    fn fail(&mut self, error: ClientError) -> Poll<()> {
+        debug!("Pipeline client failed: {error:?}");
+        for tx in self.pending.drain(..) {
+            let _ = tx.send(Err(error.clone()));
+        }
+        for tx in self.unflushed.drain(..) {
+            let _ = tx.send(Err(error.clone()));
+        }
+        self.reject_queued(&error);
+        Poll::Ready(())
+    }
+
+    fn start_send(&mut self, message: PipelineClientMessage<Req>) -> Result<(), ClientError> {
+        self.transport
+            .as_mut()
+            .start_send(message)
+            .map_err(ClientError::from_send_error)
+    }
+
+    fn poll_send(&mut self, cx: &mut Context<'_>) -> Result<(), ClientError> {
+        while !self.control.is_empty() || !self.rx_closed {
+            match self.transport.as_mut().poll_ready(cx) {
+                Poll::Ready(Ok(())) => {}
+                Poll::Ready(Err(e)) => return Err(ClientError::from_send_error(e)),
+                Poll::Pending => break,
+            }
+            if let Some(message) = self.control.pop_front() {
+                self.start_send(message)?;
+                continue;
+            }
+            match self.rx.poll_recv(cx) {
+                Poll::Ready(Some(Message::Call { frame, tx })) => {
+                    self.pending.push_back(tx);
+                    self.start_send(PipelineClientMessage::Call(frame))?;
+                }
+                Poll::Ready(Some(Message::Cast { frame, tx })) => {
+                    // The cast only counts as sent once it's been flushed
+                    match self.start_send(PipelineClientMessage::Cast(frame)) {
+                        Ok(()) => self.unflushed.push(tx),
+                        Err(e) => {
+                            let _ = tx.send(Err(e.clone()));
+                            return Err(e);
+                        }
+                    }
+                }
+                Poll::Ready(None) => self.rx_closed = true,
+                Poll::Pending => break,
+            }
+        }
+        match self.transport.as_mut().poll_flush(cx) {
+            Poll::Ready(Ok(())) => {
+                for tx in self.unflushed.drain(..) {
+                    let _ = tx.send(Ok(()));
+                }
+            }
+            Poll::Ready(Err(e)) => return Err(ClientError::from_send_error(e)),
+            Poll::Pending => {}
+        }
+        Ok(())
+    }
+
+    // Flushes anything that's left and closes the transport
+    fn poll_close(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        let res =
+            ready!(self.transport.as_mut().poll_close(cx)).map_err(ClientError::from_send_error);
+        if let Err(e) = &res {
+            debug!("Failed to close the connection: {e:?}");
+        }
+        for tx in self.unflushed.drain(..) {
+            let _ = tx.send(res.clone());
+        }
+        Poll::Ready(())
+    }
+
+    fn poll_recv(&mut self, cx: &mut Context<'_>) -> Poll<ClientError> {
+        loop {
+            let message = match self.transport.as_mut().try_poll_next(cx) {
+                Poll::Ready(Some(Ok(message))) => message,
+                Poll::Ready(Some(Err(e))) => {
+                    return Poll::Ready(ClientError::from_recv_error(e));
+                }
+                Poll::Ready(None) => return Poll::Ready(ClientError::Closed),
+                Poll::Pending => return Poll::Pending,
+            };
+            if let Some(keepalive) = &mut self.keepalive {
+                keepalive.received();
+            }
+            match message {
+                PipelineServerMessage::Response(response) => match self.pending.pop_front() {
+                    Some(tx) => {
+                        let _ = tx.send(Ok(response));
+                    }
+                    None => return Poll::Ready(ClientError::Desynchronized),
+                },
+                PipelineServerMessage::GoAway => self.go_away(),
+                PipelineServerMessage::Busy => {
+                    self.status.set_busy();
+                    return Poll::Ready(ClientError::Busy);
+                }
+                PipelineServerMessage::Ping => {
+                    self.control.push_back(PipelineClientMessage::Pong);
+                }
+                PipelineServerMessage::Pong => {}
+            }
+        }
+    }
+
+    fn poll_keepalive(&mut self, cx: &mut Context<'_>) -> Result<(), ClientError> {
+        let Some(keepalive) = &mut self.keepalive else {
+            return Ok(());
+        };
+        let idle = self.pending.is_empty();
+        while let Poll::Ready(event) = keepalive.poll(cx, idle) {
+            match event {
+                KeepaliveEvent::Ping => self.control.push_back(PipelineClientMessage::Ping),
+                KeepaliveEvent::Unresponsive => return Err(ClientError::Unresponsive),
+                KeepaliveEvent::Idle => {
+                    debug!("Closing idle connection");
+                    return Err(ClientError::Closed);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<S, Req, Res> Future for PipelineDriver<S, Req, Res>
+where
+    S: TryStream<Ok = PipelineServerMessage<Res>> + Sink<PipelineClientMessage<Req>>,
+    <S as futures::TryStream>::Error: Into<BoxError>,
+    <S as futures::Sink<PipelineClientMessage<Req>>>::Error: Into<BoxError>,
+{
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        if this.closing {
+            return this.poll_close(cx);
+        }
+        if let Err(e) = this.poll_send(cx) {
+            return this.fail(e);
+        }
+        if let Poll::Ready(e) = this.poll_recv(cx) {
+            return this.fail(e);
+        }
+        if let Err(e) = this.poll_keepalive(cx) {
+            return this.fail(e);
+        }
+        if !this.control.is_empty() {
+            if let Err(e) = this.poll_send(cx) {
+                return this.fail(e);
+            }
+        }
+        if this.rx_closed && this.pending.is_empty() {
+            // Every handle is gone or the server is going away, so nothing else can be sent
+            this.closing = true;
+            return this.poll_close(cx);
+        }
+        Poll::Pending
+    }
+}
@@ -0,0 +1,376 @@
+use std::future::{self, Future};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+
+use futures::future::Shared;
+use futures::{FutureExt, Sink, TryStream};
+use tokio::time::Sleep;
+use tower::{BoxError, Service};
+use tracing::{debug, warn};
+use transport_async::Connect;
+
+use crate::{
+    Backoff, CallOptions, Client, ClientError, ClientFuture, ClientService, Keepalive,
+    PipelineClientMessage, PipelineServerMessage,
+};
+
+type MakeClient<C, Req, Res> = Arc<dyn Fn(C, Keepalive) -> ClientService<Req, Res> + Send + Sync>;
+type BoxConnectFuture<Req, Res> =
+    Pin<Box<dyn Future<Output = Result<Arc<Connection<Req, Res>>, ClientError>> + Send>>;
+type ConnectFuture<Req, Res> = Shared<BoxConnectFuture<Req, Res>>;
+type Resend<Req> = Arc<dyn Fn(&Req) -> Req + Send + Sync>;
+
+struct Connection<Req, Res> {
+    service: Mutex<ClientService<Req, Res>>,
+    broken: Arc<AtomicBool>,
+}
+
+impl<Req, Res> Connection<Req, Res>
+where
+    Req: Send + 'static,
+    Res: Send + 'static,
+{
+    fn is_broken(&self) -> bool {
+        self.broken.load(Ordering::SeqCst)
+    }
+
+    fn set_broken(&self) {
+        self.broken.store(true, Ordering::SeqCst);
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, ClientService<Req, Res>> {
+        self.service.lock().expect("client service poisoned")
+    }
+
+    fn call_with(&self, options: CallOptions, req: Req) -> ClientFuture<Res> {
+        let res = self.lock().call_with(options, req);
+        let broken = self.broken.clone();
+        Box::pin(async move {
+            let res = res.await;
+            if let Err(e) = &res {
+                if e.is_connection_error() {
+                    broken.store(true, Ordering::SeqCst);
+                }
+            }
+            res
+        })
+    }
+
+    async fn ready_call_with(&self, options: CallOptions, req: Req) -> Result<Res, ClientError> {
+        if let Err(e) = future::poll_fn(|cx| self.lock().poll_ready(cx)).await {
+            self.set_broken();
+            return Err(e);
+        }
+        self.call_with(options, req).await
+    }
+}
+
+// The newest connection, shared with calls so they can be resent on it
+enum Slot<Req, Res> {
+    Empty,
+    Connecting(ConnectFuture<Req, Res>),
+    Connected(Arc<Connection<Req, Res>>),
+}
+
+struct Connector<C, Req, Res>
+where
+    C: Connect,
+{
+    params: C::Params,
+    make_client: MakeClient<C, Req, Res>,
+    keepalive: Keepalive,
+    slot: Arc<Mutex<Slot<Req, Res>>>,
+}
+
+impl<C, Req, Res> Clone for Connector<C, Req, Res>
+where
+    C: Connect,
+    C::Params: Clone,
+{
+    fn clone(&self) -> Self {
+        Self {
+            params: self.params.clone(),
+            make_client: self.make_client.clone(),
+            keepalive: self.keepalive.clone(),
+            slot: self.slot.clone(),
+        }
+    }
+}
+
+impl<C, Req, Res> Connector<C, Req, Res>
+where
+    C: Connect + Send + 'static,
+    C::Params: Clone + Send + 'static,
+    Req: Send + 'static,
+    Res: Send + 'static,
+{
+    /// Returns the newest connection if it still works, otherwise opens a new one. Callers that
+    /// need a connection while one is being opened share it.
+    fn connection(&self) -> ConnectFuture<Req, Res> {
+        let mut slot = self.slot.lock().expect("connection slot poisoned");
+        match &*slot {
+            Slot::Connected(connection) if !connection.is_broken() => {
+                let ready: BoxConnectFuture<Req, Res> =
+                    Box::pin(future::ready(Ok(connection.clone())));
+                return ready.shared();
+            }
+            Slot::Connecting(connect) => return connect.clone(),
+            _ => {}
+        }
+        let connect = self.connect();
+        *slot = Slot::Connecting(connect.clone());
+        connect
+    }
+
+    fn connect(&self) -> ConnectFuture<Req, Res> {
+        let params = self.params.clone();
+        let make_client = self.make_client.clone();
+        let keepalive = self.keepalive.clone();
+        // Weak so a connection attempt nobody is waiting on anymore doesn't keep itself alive
+        let slot = Arc::downgrade(&self.slot);
+        let connect: BoxConnectFuture<Req, Res> = Box::pin(async move {
+            let res = C::connect(params)
+                .await
+                .map(|conn| {
+                    Arc::new(Connection {
+                        service: Mutex::new(make_client(conn, keepalive)),
+                        broken: Default::default(),
+                    })
+                })
+                .map_err(|e| ClientError::Connect(Arc::from(BoxError::from(e))));
+            store(&slot, &res);
+            res
+        });
+        connect.shared()
+    }
+}
+
+fn store<Req, Res>(
+    slot: &Weak<Mutex<Slot<Req, Res>>>,
+    res: &Result<Arc<Connection<Req, Res>>, ClientError>,
+) {
+    let Some(slot) = slot.upgrade() else {
+        return;
+    };
+    let mut slot = slot.lock().expect("connection slot poisoned");
+    *slot = match res {
+        Ok(connection) => Slot::Connected(connection.clone()),
+        Err(_) => Slot::Empty,
+    };
+}
+
+enum State<Req, Res> {
+    Disconnected,
+    Connecting(ConnectFuture<Req, Res>),
+    Backoff(Pin<Box<Sleep>>),
+    Connected(Arc<Connection<Req, Res>>),
+}
+
+/// A client that connects on first use and opens a new connection whenever the current one
+/// fails or the server goes away.
+///
+/// Calls that were turned away because the server was going away fail with an error for which
+/// [`ClientError::is_retryable`] is true. They're only sent again automatically if
+/// [`ReconnectingClient::with_resend`] is set.
+pub struct ReconnectingClient<C, Req, Res>
+where
+    C: Connect,
+{
+    connector: Connector<C, Req, Res>,
+    backoff: Backoff,
+    resend: Option<Resend<Req>>,
+    // Consecutive failed attempts, shared with calls so a successful one can reset it
+    attempts: Arc<AtomicU32>,
+    state: State<Req, Res>,
+}
+
+impl<C, Req, Res> ReconnectingClient<C, Req, Res>
+where
+    C: Connect + Send + 'static,
+    C::Params: Clone + Send + 'static,
+    Req: Send + 'static,
+    Res: Send + 'static,
+{
+    pub fn pipeline<F, S>(params: C::Params, codec: F) -> Self
+    where
+        F: Fn(C) -> S + Send + Sync + 'static,
+        S: TryStream<Ok = PipelineServerMessage<Res>>
+            + Sink<PipelineClientMessage<Req>>
+            + Send
+            + 'static,
+        <S as TryStream>::Error: Into<BoxError>,
+        <S as Sink<PipelineClientMessage<Req>>>::Error: Into<BoxError>,
+    {
+        Self::new(params, move |conn, keepalive| {
+            Client::new(codec(conn))
+                .with_keepalive(keepalive)
+                .create_pipeline()
+        })
+    }
+
+    #[cfg(feature = "multiplex")]
+    pub fn multiplex<F, S>(params: C::Params, codec: F) -> Self
+    where
+        F: Fn(C) -> S + Send + Sync + 'static,
+        S: TryStream<Ok = crate::ServerMessage<Res>>
+            + Sink<crate::ClientMessage<Req>>
+            + Send
+            + 'static,
+        <S as TryStream>::Error: Into<BoxError>,
+        <S as Sink<crate::ClientMessage<Req>>>::Error: Into<BoxError>,
+    {
+        Self::new(params, move |conn, keepalive| {
+            Client::new(codec(conn))
+                .with_keepalive(keepalive)
+                .create_multiplex()
+        })
+    }
+
+    fn new<F>(params: C::Params, make_client: F) -> Self
+    where
+        F: Fn(C, Keepalive) -> ClientService<Req, Res> + Send + Sync + 'static,
+    {
+        Self {
+            connector: Connector {
+                params,
+                make_client: Arc::new(make_client),
+                keepalive: Keepalive::default(),
+                slot: Arc::new(Mutex::new(Slot::Empty)),
+            },
+            backoff: Backoff::default(),
+            resend: None,
+            attempts: Default::default(),
+            state: State::Disconnected,
+        }
+    }
+
+    /// Backoff between connection attempts. Failed connections and connections the server turns
+    /// away as busy both count as failed attempts until a call succeeds.
+    pub fn with_backoff(mut self, backoff: Backoff) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    /// Keepalive settings for each connection. A connection that stops answering pings is
+    /// treated as broken and replaced on the next call.
+    pub fn with_keepalive(mut self, keepalive: Keepalive) -> Self {
+        self.connector.keepalive = keepalive;
+        self
+    }
+
+    /// Sends calls that fail with a [retryable](ClientError::is_retryable) error again on a new
+    /// connection. This happens when the server goes away before it starts running the call, so
+    /// the call is never run twice. Each call is sent again at most once.
+    pub fn with_resend(mut self) -> Self
+    where
+        Req: Clone,
+    {
+        self.resend = Some(Arc::new(Req::clone));
+        self
+    }
+
+    /// Sends a request with the supplied options. Like [`Service::call`], this must only be
+    /// called after the service has been polled to readiness.
+    pub fn call_with(&mut self, options: CallOptions, req: Req) -> ClientFuture<Res> {
+        let State::Connected(connection) = &self.state else {
+            return Box::pin(future::ready(Err(ClientError::Closed)));
+        };
+        let resend = self.resend.as_ref().map(|resend| resend(&req));
+        let res = connection.call_with(options.clone(), req);
+        let connector = self.connector.clone();
+        let attempts = self.attempts.clone();
+        Box::pin(async move {
+            let res = match (res.await, resend) {
+                (Err(e), Some(req)) if e.is_retryable() => {
+                    debug!("Sending call again on a new connection: {e:?}");
+                    let connection = connector.connection().await?;
+                    connection.ready_call_with(options, req).await
+                }
+                (res, _) => res,
+            };
+            if res.is_ok() {
+                attempts.store(0, Ordering::SeqCst);
+            }
+            res
+        })
+    }
+
+    fn next_delay(&self) -> Option<Duration> {
+        let attempts = self.attempts.fetch_add(1, Ordering::SeqCst) + 1;
+        if self.backoff.exhausted(attempts) {
+            self.attempts.store(0, Ordering::SeqCst);
+            return None;
+        }
+        Some(self.backoff.delay(attempts))
+    }
+}
+
+impl<C, Req, Res> Service<Req> for ReconnectingClient<C, Req, Res>
+where
+    C: Connect + Send + 'static,
+    C::Params: Clone + Send + 'static,
+    Req: Send + 'static,
+    Res: Send + 'static,
+{
+    type Response = Res;
+    type Error = ClientError;
+    type Future = ClientFuture<Res>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        loop {
+            match &mut self.state {
+                State::Disconnected => {
+                    self.state = State::Connecting(self.connector.connection());
+                }
+                State::Connecting(connect) => match ready!(connect.poll_unpin(cx)) {
+                    Ok(connection) => self.state = State::Connected(connection),
+                    Err(e) => {
+                        let Some(delay) = self.next_delay() else {
+                            self.state = State::Disconnected;
+                            return Poll::Ready(Err(e));
+                        };
+                        warn!("Failed to connect, retrying in {delay:?}: {e:?}");
+                        self.state = State::Backoff(Box::pin(tokio::time::sleep(delay)));
+                    }
+                },
+                State::Backoff(sleep) => {
+                    ready!(sleep.as_mut().poll(cx));
+                    self.state = State::Connecting(self.connector.connection());
+                }
+                State::Connected(connection) => {
+                    let res = ready!(connection.lock().poll_ready(cx));
+                    match res {
+                        // Reconnecting right away would most likely be rejected again
+                        Err(ClientError::Busy) => {
+                            connection.set_broken();
+                            let Some(delay) = self.next_delay() else {
+                                self.state = State::Disconnected;
+                                return Poll::Ready(Err(ClientError::Busy));
+                            };
+                            debug!("Server is busy, reconnecting in {delay:?}");
+                            self.state = State::Backoff(Box::pin(tokio::time::sleep(delay)));
+                        }
+                        _ if connection.is_broken() => {
+                            debug!("Connection failed, reconnecting");
+                            self.state = State::Disconnected;
+                        }
+                        Ok(()) => return Poll::Ready(Ok(())),
+                        Err(e) if e.is_connection_error() => {
+                            debug!("Connection failed, reconnecting: {e:?}");
+                            connection.set_broken();
+                            self.state = State::Disconnected;
+                        }
+                        Err(e) => return Poll::Ready(Err(e)),
+                    }
+                }
+            }
+        }
+    }
+
+    fn call(&mut self, req: Req) -> Self::Future {
+        self.call_with(CallOptions::default(), req)
+    }
+}
@@ -0,0 +1,251 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use futures::Stream;
+use pin_project_lite::pin_project;
+use tokio::time::Sleep;
+use tower::util::BoxService;
+use tower::Service;
+
+use crate::{ClientError, Metadata, RequestFrame, ResponseFrame};
+
+#[derive(Clone, Debug, Default)]
+pub struct CallOptions {
+    deadline: Option<Instant>,
+    metadata: Metadata,
+}
+
+impl CallOptions {
+    pub fn with_deadline(mut self, deadline: Instant) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    pub fn with_timeout(self, timeout: Duration) -> Self {
+        self.with_deadline(Instant::now() + timeout)
+    }
+
+    pub fn with_metadata(mut self, metadata: Metadata) -> Self {
+        self.metadata = metadata;
+        self
+    }
+
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    pub fn metadata(&self) -> &Metadata {
+        &self.metadata
+    }
+
+    fn into_frame<T>(self, value: T) -> RequestFrame<T> {
+        RequestFrame::new(value)
+            .with_deadline(self.deadline)
+            .with_metadata(self.metadata)
+    }
+}
+
+pub type ClientFuture<Res> = Pin<Box<dyn Future<Output = Result<Res, ClientError>> + Send>>;
+
+pub type ClientStream<Res> = Pin<Box<dyn Stream<Item = Result<Res, ClientError>> + Send>>;
+
+// Acknowledges that a one-way request was flushed to the transport
+pub(crate) type CastSender = tokio::sync::oneshot::Sender<Result<(), ClientError>>;
+
+pub(crate) type Caster<Req> = Box<dyn Fn(RequestFrame<Req>) -> ClientFuture<()> + Send>;
+
+pub struct ClientService<Req, Res> {
+    inner: BoxService<RequestFrame<Req>, ResponseFrame<Res>, ClientError>,
+    caster: Caster<Req>,
+    #[cfg(feature = "multiplex")]
+    multiplex: Option<super::multiplex::MultiplexClient<Req, Res>>,
+}
+
+impl<Req, Res> ClientService<Req, Res>
+where
+    Req: Send + 'static,
+    Res: Send + 'static,
+{
+    pub(crate) fn new(
+        inner: BoxService<RequestFrame<Req>, ResponseFrame<Res>, ClientError>,
+        caster: Caster<Req>,
+    ) -> Self {
+        Self {
+            inner,
+            caster,
+            #[cfg(feature = "multiplex")]
+            multiplex: None,
+        }
+    }
+
+    #[cfg(feature = "multiplex")]
+    pub(crate) fn with_multiplex(
+        mut self,
+        client: super::multiplex::MultiplexClient<Req, Res>,
+    ) -> Self {
+        self.multiplex = Some(client);
+        self
+    }
+
+    /// Sends a request with the supplied options. Like [`Service::call`], this must only be
+    /// called after the service has been polled to readiness.
+    pub fn call_with(&mut self, options: CallOptions, req: Req) -> ClientFuture<Res> {
+        let res = self.call_with_metadata(options, req);
+        Box::pin(async move { Ok(res.await?.into_result()?) })
+    }
+
+    /// Sends a request and returns the whole response frame, which includes the trailing metadata
+    /// set by the handler. Like [`Service::call`], this must only be called after the service has
+    /// been polled to readiness.
+    pub fn call_with_metadata(
+        &mut self,
+        options: CallOptions,
+        req: Req,
+    ) -> ClientFuture<ResponseFrame<Res>> {
+        let deadline = options.deadline;
+        let res = self.inner.call(options.into_frame(req));
+        limit_future(deadline, res)
+    }
+
+    /// Sends a one-way request. The server runs the handler but doesn't send a response, so the
+    /// returned future completes as soon as the request has been flushed to the transport.
+    pub fn cast(&mut self, req: Req) -> ClientFuture<()> {
+        self.cast_with(CallOptions::default(), req)
+    }
+
+    /// Sends a one-way request with the supplied options.
+    pub fn cast_with(&mut self, options: CallOptions, req: Req) -> ClientFuture<()> {
+        (self.caster)(options.into_frame(req))
+    }
+
+    /// Sends a request whose response is a stream of items. Streaming is only supported on
+    /// multiplexed connections.
+    pub fn call_streaming(&mut self, options: CallOptions, req: Req) -> ClientStream<Res> {
+        #[cfg(feature = "multiplex")]
+        if let Some(client) = &self.multiplex {
+            let deadline = options.deadline;
+            let stream = client.call_streaming(options.into_frame(req));
+            return limit_stream(deadline, stream);
+        }
+        let _ = (options, req);
+        Box::pin(futures::stream::once(std::future::ready(Err(
+            ClientError::StreamingUnsupported,
+        ))))
+    }
+
+    /// Sends a request as a stream of items and waits for a single response. Streaming is only
+    /// supported on multiplexed connections.
+    pub fn call_client_streaming<S>(
+        &mut self,
+        options: CallOptions,
+        requests: S,
+    ) -> ClientFuture<Res>
+    where
+        S: Stream<Item = Req> + Send + 'static,
+    {
+        #[cfg(feature = "multiplex")]
+        if let Some(client) = &self.multiplex {
+            let deadline = options.deadline;
+            let res = client.call_client_streaming(options.into_frame(()), requests);
+            return limit_future(deadline, res);
+        }
+        let _ = (options, requests);
+        Box::pin(std::future::ready(Err(ClientError::StreamingUnsupported)))
+    }
+
+    /// Sends a request as a stream of items and receives the response as a stream of items.
+    /// Responses can arrive before the request stream has finished. Streaming is only supported
+    /// on multiplexed connections.
+    pub fn call_bidirectional<S>(&mut self, options: CallOptions, requests: S) -> ClientStream<Res>
+    where
+        S: Stream<Item = Req> + Send + 'static,
+    {
+        #[cfg(feature = "multiplex")]
+        if let Some(client) = &self.multiplex {
+            let deadline = options.deadline;
+            let stream = client.call_bidirectional(options.into_frame(()), requests);
+            return limit_stream(deadline, stream);
+        }
+        let _ = (options, requests);
+        Box::pin(futures::stream::once(std::future::ready(Err(
+            ClientError::StreamingUnsupported,
+        ))))
+    }
+}
+
+fn limit_future<Res>(deadline: Option<Instant>, res: ClientFuture<Res>) -> ClientFuture<Res>
+where
+    Res: 'static,
+{
+    match deadline {
+        Some(deadline) => Box::pin(async move {
+            // The server enforces the deadline too, but we can't rely on it responding
+            tokio::time::timeout_at(deadline.into(), res)
+                .await
+                .unwrap_or(Err(ClientError::DeadlineExceeded))
+        }),
+        None => res,
+    }
+}
+
+// Streaming calls are only available with the multiplex feature
+#[cfg_attr(not(feature = "multiplex"), allow(dead_code))]
+fn limit_stream<Res>(deadline: Option<Instant>, stream: ClientStream<Res>) -> ClientStream<Res>
+where
+    Res: Send + 'static,
+{
+    match deadline {
+        Some(deadline) => Box::pin(DeadlineStream {
+            stream,
+            deadline: tokio::time::sleep_until(deadline.into()),
+            expired: false,
+        }),
+        None => stream,
+    }
+}
+
+pin_project! {
+    // Ends the stream with an error once the deadline passes in case the server stops responding
+    struct DeadlineStream<Res> {
+        stream: ClientStream<Res>,
+        #[pin]
+        deadline: Sleep,
+        expired: bool,
+    }
+}
+
+impl<Res> Stream for DeadlineStream<Res> {
+    type Item = Result<Res, ClientError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.project();
+        if *this.expired {
+            return Poll::Ready(None);
+        }
+        if this.deadline.poll(cx).is_ready() {
+            *this.expired = true;
+            return Poll::Ready(Some(Err(ClientError::DeadlineExceeded)));
+        }
+        this.stream.as_mut().poll_next(cx)
+    }
+}
+
+impl<Req, Res> Service<Req> for ClientService<Req, Res>
+where
+    Req: Send + 'static,
+    Res: Send + 'static,
+{
+    type Response = Res;
+    type Error = ClientError;
+    type Future = ClientFuture<Res>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Req) -> Self::Future {
+        self.call_with(CallOptions::default(), req)
+    }
+}
@@ -0,0 +1,79 @@
+use std::convert::Infallible;
+
+use tower::BoxError;
+use tracing::debug;
+
+#[cfg_attr(feature = "codec", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum RemoteErrorKind {
+    /// The handler returned an error.
+    Service,
+    /// The handler did not finish before the request's deadline.
+    DeadlineExceeded,
+    /// The request was sent in a form the handler does not accept, such as a stream of items
+    /// sent to a handler that expects a single value.
+    InvalidRequest,
+    /// The server is shutting down and isn't accepting new requests.
+    Unavailable,
+    /// The handler panicked while processing the request.
+    Internal,
+    /// The server has too many requests running and rejected this one.
+    Overloaded,
+    /// The server's access policy doesn't allow the peer to make this request.
+    PermissionDenied,
+}
+
+/// The error sent to the client when a request fails.
+///
+/// Handlers choose what the client sees by returning an error that converts into a
+/// `RemoteError`. Implement `From<YourError> for RemoteError` to pick the kind and message for
+/// each of your errors, or return a `RemoteError` directly.
+#[cfg_attr(feature = "codec", derive(serde::Serialize, serde::Deserialize))]
+#[derive(thiserror::Error, Clone, Debug, PartialEq, Eq)]
+#[error("{kind:?}: {message}")]
+pub struct RemoteError {
+    kind: RemoteErrorKind,
+    message: String,
+}
+
+impl RemoteError {
+    pub fn new(kind: RemoteErrorKind, message: impl Into<String>) -> Self {
+        Self {
+            kind,
+            message: message.into(),
+        }
+    }
+
+    /// An error returned by the handler.
+    pub fn service(message: impl Into<String>) -> Self {
+        Self::new(RemoteErrorKind::Service, message)
+    }
+
+    pub fn kind(&self) -> RemoteErrorKind {
+        self.kind
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+}
+
+impl From<Infallible> for RemoteError {
+    fn from(e: Infallible) -> Self {
+        match e {}
+    }
+}
+
+// Boxed errors may contain internal details, so only a `RemoteError` that was boxed by the
+// handler is passed through
+impl From<BoxError> for RemoteError {
+    fn from(e: BoxError) -> Self {
+        match e.downcast::<RemoteError>() {
+            Ok(e) => *e,
+            Err(e) => {
+                debug!("Handler returned an error: {e:?}");
+                RemoteError::service("request failed")
+            }
+        }
+    }
+}
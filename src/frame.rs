@@ -0,0 +1,228 @@
+use std::time::{Duration, Instant};
+
+use crate::{Metadata, RemoteError};
+
+#[cfg_attr(feature = "codec", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Clone, Debug)]
+pub struct RequestFrame<T> {
+    /// Time remaining until the deadline when the request was sent. Relative timeouts are used
+    /// on the wire so the client and server clocks don't need to agree.
+    pub(crate) timeout: Option<Duration>,
+    pub(crate) metadata: Metadata,
+    pub(crate) value: T,
+}
+
+impl<T> RequestFrame<T> {
+    pub fn new(value: T) -> Self {
+        Self {
+            timeout: None,
+            metadata: Metadata::default(),
+            value,
+        }
+    }
+
+    pub fn with_deadline(mut self, deadline: Option<Instant>) -> Self {
+        self.timeout = deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()));
+        self
+    }
+
+    pub fn with_metadata(mut self, metadata: Metadata) -> Self {
+        self.metadata = metadata;
+        self
+    }
+
+    pub fn metadata(&self) -> &Metadata {
+        &self.metadata
+    }
+
+    // Timeouts come from the peer, so one too far out to be an `Instant` is treated as no deadline
+    // rather than overflowing
+    pub(crate) fn deadline(&self) -> Option<Instant> {
+        self.timeout
+            .and_then(|timeout| Instant::now().checked_add(timeout))
+    }
+
+    pub(crate) fn map<U>(self, f: impl FnOnce(T) -> U) -> RequestFrame<U> {
+        RequestFrame {
+            timeout: self.timeout,
+            metadata: self.metadata,
+            value: f(self.value),
+        }
+    }
+}
+
+#[cfg(all(feature = "server", feature = "multiplex"))]
+impl<T, E> RequestFrame<Result<T, E>> {
+    pub(crate) fn transpose(self) -> Result<RequestFrame<T>, E> {
+        let value = self.value?;
+        Ok(RequestFrame {
+            timeout: self.timeout,
+            metadata: self.metadata,
+            value,
+        })
+    }
+}
+
+#[cfg_attr(feature = "codec", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Clone, Debug)]
+pub enum PipelineClientMessage<T> {
+    /// A request that expects a response.
+    Call(RequestFrame<T>),
+    /// A one-way request. The server doesn't send anything back.
+    Cast(RequestFrame<T>),
+    /// Checks that the other side is still responding. It replies with `Pong`.
+    Ping,
+    /// The reply to a `Ping`.
+    Pong,
+}
+
+#[cfg(feature = "multiplex")]
+#[cfg_attr(feature = "codec", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Clone, Debug)]
+pub enum ClientMessage<T> {
+    /// A frame belonging to the request with the given tag.
+    Call(crate::Tagged<ClientFrame<T>>),
+    /// A one-way request. It isn't assigned a tag since the server doesn't send anything back.
+    Cast(RequestFrame<T>),
+    /// Checks that the other side is still responding. It replies with `Pong`.
+    Ping,
+    /// The reply to a `Ping`.
+    Pong,
+}
+
+/// Number of items a stream can get ahead of the side reading it. The reader sends a
+/// [`ClientFrame::Credit`] or [`ServerFrame::Credit`] as it consumes items to let the writer
+/// send more.
+#[cfg(feature = "multiplex")]
+pub(crate) const STREAM_WINDOW: u32 = 32;
+
+#[cfg(feature = "multiplex")]
+#[cfg_attr(feature = "codec", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Clone, Debug)]
+pub enum ClientFrame<T> {
+    Request(RequestFrame<T>),
+    /// Starts a request whose value is sent as a stream of [`ClientFrame::Item`] frames.
+    Open(RequestFrame<()>),
+    /// One item of a streaming request.
+    Item(T),
+    /// The streaming request with the same tag has no more items.
+    End,
+    /// The caller is no longer waiting for the response to the request with the same tag.
+    Cancel,
+    /// The caller consumed this many items of the streaming response with the same tag, so the
+    /// server may send as many more.
+    Credit(u32),
+}
+
+#[cfg_attr(feature = "codec", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Clone, Debug)]
+pub struct ResponseFrame<T> {
+    pub(crate) result: Result<T, RemoteError>,
+    /// Trailing metadata set by the handler.
+    pub(crate) metadata: Metadata,
+}
+
+impl<T> ResponseFrame<T> {
+    pub fn ok(value: T) -> Self {
+        Ok(value).into()
+    }
+
+    pub fn err(error: RemoteError) -> Self {
+        Err(error).into()
+    }
+
+    pub fn with_metadata(mut self, metadata: Metadata) -> Self {
+        self.metadata = metadata;
+        self
+    }
+
+    pub fn metadata(&self) -> &Metadata {
+        &self.metadata
+    }
+
+    pub fn into_result(self) -> Result<T, RemoteError> {
+        self.result
+    }
+
+    pub fn into_parts(self) -> (Result<T, RemoteError>, Metadata) {
+        (self.result, self.metadata)
+    }
+}
+
+impl<T> From<Result<T, RemoteError>> for ResponseFrame<T> {
+    fn from(result: Result<T, RemoteError>) -> Self {
+        Self {
+            result,
+            metadata: Metadata::default(),
+        }
+    }
+}
+
+#[cfg_attr(feature = "codec", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Clone, Debug)]
+pub enum PipelineServerMessage<T> {
+    /// The response to the oldest request that hasn't been answered yet.
+    Response(ResponseFrame<T>),
+    /// The server is shutting down. Requests that are already running will still complete, but
+    /// new requests should be sent on a different connection.
+    GoAway,
+    /// The server has too many connections open and is closing this one without reading from it.
+    Busy,
+    /// Checks that the other side is still responding. It replies with `Pong`.
+    Ping,
+    /// The reply to a `Ping`.
+    Pong,
+}
+
+#[cfg(feature = "multiplex")]
+#[cfg_attr(feature = "codec", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Clone, Debug)]
+pub enum ServerFrame<T> {
+    /// The only response to a unary request, or the final frame of a streaming response that
+    /// failed before it finished.
+    Response(ResponseFrame<T>),
+    /// One item of a streaming response. More items may follow until the stream ends.
+    Item(ResponseFrame<T>),
+    /// The streaming response with the same tag has finished.
+    End,
+    /// The handler consumed this many items of the streaming request with the same tag, so the
+    /// caller may send as many more.
+    Credit(u32),
+}
+
+#[cfg(feature = "multiplex")]
+#[cfg_attr(feature = "codec", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Clone, Debug)]
+pub enum ServerMessage<T> {
+    /// A frame belonging to the request with the given tag.
+    Call(crate::Tagged<ServerFrame<T>>),
+    /// A message the server sent on its own rather than in response to a request.
+    Notification(T),
+    /// The server is shutting down. Requests that are already running will still complete, but
+    /// new requests should be sent on a different connection.
+    GoAway,
+    /// The server has too many connections open and is closing this one without reading from it.
+    Busy,
+    /// Checks that the other side is still responding. It replies with `Pong`.
+    Ping,
+    /// The reply to a `Ping`.
+    Pong,
+}
+
+#[cfg(feature = "codec")]
+pub fn pipeline_codec<Req, Res>(
+    codec: transport_async::codec::Codec,
+) -> transport_async::codec::SerdeCodec<PipelineClientMessage<Req>, PipelineServerMessage<Res>> {
+    use transport_async::codec::SerdeCodec;
+
+    SerdeCodec::<PipelineClientMessage<Req>, PipelineServerMessage<Res>>::new(codec)
+}
+
+#[cfg(all(feature = "codec", feature = "multiplex"))]
+pub fn multiplex_codec<Req, Res>(
+    codec: transport_async::codec::Codec,
+) -> transport_async::codec::SerdeCodec<ClientMessage<Req>, ServerMessage<Res>> {
+    use transport_async::codec::SerdeCodec;
+
+    SerdeCodec::<ClientMessage<Req>, ServerMessage<Res>>::new(codec)
+}
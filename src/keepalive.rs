@@ -0,0 +1,129 @@
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use tokio::time::{Instant, Sleep};
+
+/// Settings for detecting unresponsive peers and closing idle connections. Both are disabled by
+/// default.
+#[derive(Clone, Debug)]
+pub struct Keepalive {
+    ping_interval: Option<Duration>,
+    max_missed_pings: u32,
+    idle_timeout: Option<Duration>,
+}
+
+impl Default for Keepalive {
+    fn default() -> Self {
+        Self {
+            ping_interval: None,
+            max_missed_pings: 3,
+            idle_timeout: None,
+        }
+    }
+}
+
+impl Keepalive {
+    /// Sends a ping whenever `interval` passes without hearing anything from the peer.
+    pub fn with_ping_interval(mut self, interval: Duration) -> Self {
+        self.ping_interval = Some(interval);
+        self
+    }
+
+    /// Closes the connection once this many pings in a row go unanswered. Defaults to 3.
+    pub fn with_max_missed_pings(mut self, max_missed_pings: u32) -> Self {
+        self.max_missed_pings = max_missed_pings.max(1);
+        self
+    }
+
+    /// Closes the connection once it has gone `timeout` without any requests running.
+    pub fn with_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = Some(timeout);
+        self
+    }
+}
+
+pub(crate) enum KeepaliveEvent {
+    /// A ping should be sent to the peer.
+    Ping,
+    /// The peer didn't answer enough pings in a row.
+    Unresponsive,
+    /// The connection has been idle for too long.
+    Idle,
+}
+
+pub(crate) struct KeepaliveTimer {
+    config: Keepalive,
+    sleep: Pin<Box<Sleep>>,
+    next_ping: Instant,
+    last_active: Instant,
+    missed_pings: u32,
+}
+
+impl KeepaliveTimer {
+    /// Returns `None` if neither pings nor the idle timeout are enabled.
+    pub(crate) fn new(config: Keepalive) -> Option<Self> {
+        if config.ping_interval.is_none() && config.idle_timeout.is_none() {
+            return None;
+        }
+        let now = Instant::now();
+        Some(Self {
+            next_ping: now + config.ping_interval.unwrap_or_default(),
+            config,
+            sleep: Box::pin(tokio::time::sleep_until(now)),
+            last_active: now,
+            missed_pings: 0,
+        })
+    }
+
+    /// Records that something was received from the peer, which proves it's still alive.
+    pub(crate) fn received(&mut self) {
+        if let Some(interval) = self.config.ping_interval {
+            self.missed_pings = 0;
+            self.next_ping = Instant::now() + interval;
+        }
+    }
+
+    /// `idle` should be true when the connection has no requests running.
+    pub(crate) fn poll(&mut self, cx: &mut Context<'_>, idle: bool) -> Poll<KeepaliveEvent> {
+        let now = Instant::now();
+        if !idle {
+            self.last_active = now;
+        }
+        let idle_deadline = self
+            .config
+            .idle_timeout
+            .filter(|_| idle)
+            .map(|timeout| self.last_active + timeout);
+        if idle_deadline.is_some_and(|deadline| now >= deadline) {
+            return Poll::Ready(KeepaliveEvent::Idle);
+        }
+
+        let mut next_ping = None;
+        if let Some(interval) = self.config.ping_interval {
+            if now >= self.next_ping {
+                if self.missed_pings >= self.config.max_missed_pings {
+                    return Poll::Ready(KeepaliveEvent::Unresponsive);
+                }
+                self.missed_pings += 1;
+                self.next_ping = now + interval;
+                return Poll::Ready(KeepaliveEvent::Ping);
+            }
+            next_ping = Some(self.next_ping);
+        }
+
+        // Nothing to wait for until the connection goes idle, which the caller will notice on
+        // its own
+        let Some(deadline) = next_ping.into_iter().chain(idle_deadline).min() else {
+            return Poll::Pending;
+        };
+        self.sleep.as_mut().reset(deadline);
+        match self.sleep.as_mut().poll(cx) {
+            Poll::Ready(()) => {
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
@@ -15,6 +15,22 @@ pub use client::*;
 mod tagged;
 #[cfg(feature = "multiplex")]
 pub use tagged::*;
+mod backoff;
+pub use backoff::*;
+mod error;
+pub use error::*;
+mod extensions;
+pub use extensions::*;
+mod frame;
+pub use frame::*;
+mod keepalive;
+pub use keepalive::*;
+mod metadata;
+pub use metadata::*;
+mod notifier;
+pub use notifier::*;
+mod raw;
+pub use raw::*;
 mod request;
 pub mod transport {
     pub use transport_async::*;
@@ -0,0 +1,102 @@
+use std::collections::{hash_map, HashMap};
+use std::sync::{Arc, Mutex};
+
+/// Out-of-band key-value pairs sent alongside a request or response, such as auth tokens or
+/// request IDs.
+#[cfg_attr(feature = "codec", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Metadata {
+    entries: HashMap<String, String>,
+}
+
+impl Metadata {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.insert(key, value);
+        self
+    }
+
+    /// Sets the value for the key, returning the previous value if there was one.
+    pub fn insert(&mut self, key: impl Into<String>, value: impl Into<String>) -> Option<String> {
+        self.entries.insert(key.into(), value.into())
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.entries.get(key).map(String::as_str)
+    }
+
+    pub fn remove(&mut self, key: &str) -> Option<String> {
+        self.entries.remove(key)
+    }
+
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.entries.contains_key(key)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.entries.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+impl<K, V> FromIterator<(K, V)> for Metadata
+where
+    K: Into<String>,
+    V: Into<String>,
+{
+    fn from_iter<I: IntoIterator<Item = (K, V)>>(iter: I) -> Self {
+        Self {
+            entries: iter
+                .into_iter()
+                .map(|(k, v)| (k.into(), v.into()))
+                .collect(),
+        }
+    }
+}
+
+impl IntoIterator for Metadata {
+    type Item = (String, String);
+    type IntoIter = hash_map::IntoIter<String, String>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.entries.into_iter()
+    }
+}
+
+/// Trailing metadata a handler can set while processing a request. It's sent to the client along
+/// with the response once the handler finishes.
+#[derive(Clone, Debug, Default)]
+pub struct Trailers {
+    inner: Arc<Mutex<Metadata>>,
+}
+
+impl Trailers {
+    pub fn insert(&self, key: impl Into<String>, value: impl Into<String>) -> Option<String> {
+        self.inner
+            .lock()
+            .expect("trailers lock poisoned")
+            .insert(key, value)
+    }
+
+    pub fn remove(&self, key: &str) -> Option<String> {
+        self.inner
+            .lock()
+            .expect("trailers lock poisoned")
+            .remove(key)
+    }
+
+    #[cfg(feature = "server")]
+    pub(crate) fn take(&self) -> Metadata {
+        std::mem::take(&mut *self.inner.lock().expect("trailers lock poisoned"))
+    }
+}
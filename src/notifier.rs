@@ -0,0 +1,63 @@
+use tokio::sync::mpsc;
+
+/// Number of notifications that can be waiting to be sent on a connection before
+/// [`Notifier::notify`] waits and [`Notifier::try_notify`] fails.
+pub const NOTIFICATION_BUFFER: usize = 32;
+
+#[derive(thiserror::Error, Debug)]
+pub enum NotificationError {
+    #[error("connection closed")]
+    Closed,
+    #[error("too many notifications are waiting to be sent")]
+    Full,
+}
+
+/// Sends notifications to the client on the other end of a multiplexed connection, outside of
+/// any request. `T` is the connection's response type.
+///
+/// Handlers on multiplexed connections find it in the request's extensions, see
+/// [`Request::notifier`](crate::Request::notifier). Notifications are queued in a bounded buffer
+/// so a client that doesn't keep up slows the sender down instead of using up memory.
+#[derive(Debug)]
+pub struct Notifier<T> {
+    tx: mpsc::Sender<T>,
+}
+
+impl<T> Clone for Notifier<T> {
+    fn clone(&self) -> Self {
+        Self {
+            tx: self.tx.clone(),
+        }
+    }
+}
+
+impl<T> Notifier<T>
+where
+    T: Send + 'static,
+{
+    #[cfg(all(feature = "server", feature = "multiplex"))]
+    pub(crate) fn channel() -> (Self, mpsc::Receiver<T>) {
+        let (tx, rx) = mpsc::channel(NOTIFICATION_BUFFER);
+        (Self { tx }, rx)
+    }
+
+    /// Queues a notification, waiting for room if the buffer is full.
+    pub async fn notify(&self, value: T) -> Result<(), NotificationError> {
+        self.tx
+            .send(value)
+            .await
+            .map_err(|_| NotificationError::Closed)
+    }
+
+    /// Queues a notification if there's room in the buffer.
+    pub fn try_notify(&self, value: T) -> Result<(), NotificationError> {
+        self.tx.try_send(value).map_err(|e| match e {
+            mpsc::error::TrySendError::Full(_) => NotificationError::Full,
+            mpsc::error::TrySendError::Closed(_) => NotificationError::Closed,
+        })
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.tx.is_closed()
+    }
+}
@@ -0,0 +1,376 @@
+use std::io;
+use std::marker::PhantomData;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+
+use bytes::{BufMut, Bytes, BytesMut};
+use futures::{Sink, Stream};
+use pin_project_lite::pin_project;
+
+use crate::{
+    Metadata, PipelineClientMessage, PipelineServerMessage, RemoteError, RemoteErrorKind,
+    RequestFrame, ResponseFrame,
+};
+
+pin_project! {
+    /// Carries a pipeline connection over a transport of raw byte messages, such as the
+    /// length-delimited framing from `transport::codec`, without needing serde. Request and
+    /// response values are the bytes of each message.
+    ///
+    /// Frames are written with a small fixed layout of their own, so both ends of the connection
+    /// need to use `RawFrames`.
+    pub struct RawFrames<T, In, Out> {
+        #[pin]
+        inner: T,
+        _phantom: PhantomData<fn() -> (In, Out)>,
+    }
+}
+
+impl<T> RawFrames<T, PipelineClientMessage<Bytes>, PipelineServerMessage<Bytes>> {
+    /// Wraps a connection accepted by the server.
+    pub fn server(inner: T) -> Self {
+        Self {
+            inner,
+            _phantom: PhantomData,
+        }
+    }
+}
+
+impl<T> RawFrames<T, PipelineServerMessage<Bytes>, PipelineClientMessage<Bytes>> {
+    /// Wraps the client's connection to the server.
+    pub fn client(inner: T) -> Self {
+        Self {
+            inner,
+            _phantom: PhantomData,
+        }
+    }
+}
+
+impl<T, In, Out> RawFrames<T, In, Out> {
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+impl<T, E, In, Out> Stream for RawFrames<T, In, Out>
+where
+    T: Stream<Item = Result<BytesMut, E>>,
+    E: From<io::Error>,
+    In: RawFrame,
+{
+    type Item = Result<In, E>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let frame = ready!(self.project().inner.poll_next(cx));
+        Poll::Ready(frame.map(|frame| Ok(In::decode(frame?.freeze())?)))
+    }
+}
+
+impl<T, In, Out> Sink<Out> for RawFrames<T, In, Out>
+where
+    T: Sink<Bytes>,
+    Out: RawFrame,
+{
+    type Error = T::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.project().inner.poll_ready(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: Out) -> Result<(), Self::Error> {
+        let mut dst = BytesMut::new();
+        item.encode(&mut dst);
+        self.project().inner.start_send(dst.freeze())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.project().inner.poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.project().inner.poll_close(cx)
+    }
+}
+
+/// Messages that [`RawFrames`] knows how to write as bytes.
+pub trait RawFrame: crate::private::Sealed + Sized {
+    #[doc(hidden)]
+    fn encode(self, dst: &mut BytesMut);
+    #[doc(hidden)]
+    fn decode(src: Bytes) -> io::Result<Self>;
+}
+
+// Every message starts with a byte that says which variant it is. Values come last and take up
+// the rest of the message so they don't need a length.
+const CALL: u8 = 0;
+const CAST: u8 = 1;
+const RESPONSE: u8 = 2;
+const GO_AWAY: u8 = 3;
+const BUSY: u8 = 4;
+const PING: u8 = 5;
+const PONG: u8 = 6;
+
+impl crate::private::Sealed for PipelineClientMessage<Bytes> {}
+
+impl RawFrame for PipelineClientMessage<Bytes> {
+    fn encode(self, dst: &mut BytesMut) {
+        match self {
+            Self::Call(request) => {
+                dst.put_u8(CALL);
+                put_request(dst, request);
+            }
+            Self::Cast(request) => {
+                dst.put_u8(CAST);
+                put_request(dst, request);
+            }
+            Self::Ping => dst.put_u8(PING),
+            Self::Pong => dst.put_u8(PONG),
+        }
+    }
+
+    fn decode(mut src: Bytes) -> io::Result<Self> {
+        match get_u8(&mut src)? {
+            CALL => Ok(Self::Call(get_request(src)?)),
+            CAST => Ok(Self::Cast(get_request(src)?)),
+            PING => Ok(Self::Ping),
+            PONG => Ok(Self::Pong),
+            other => Err(invalid(format!("unknown client message {other}"))),
+        }
+    }
+}
+
+impl crate::private::Sealed for PipelineServerMessage<Bytes> {}
+
+impl RawFrame for PipelineServerMessage<Bytes> {
+    fn encode(self, dst: &mut BytesMut) {
+        match self {
+            Self::Response(response) => {
+                dst.put_u8(RESPONSE);
+                put_response(dst, response);
+            }
+            Self::GoAway => dst.put_u8(GO_AWAY),
+            Self::Busy => dst.put_u8(BUSY),
+            Self::Ping => dst.put_u8(PING),
+            Self::Pong => dst.put_u8(PONG),
+        }
+    }
+
+    fn decode(mut src: Bytes) -> io::Result<Self> {
+        match get_u8(&mut src)? {
+            RESPONSE => Ok(Self::Response(get_response(src)?)),
+            GO_AWAY => Ok(Self::GoAway),
+            BUSY => Ok(Self::Busy),
+            PING => Ok(Self::Ping),
+            PONG => Ok(Self::Pong),
+            other => Err(invalid(format!("unknown server message {other}"))),
+        }
+    }
+}
+
+fn put_request(dst: &mut BytesMut, request: RequestFrame<Bytes>) {
+    match request.timeout {
+        Some(timeout) => {
+            dst.put_u8(1);
+            dst.put_u64(timeout.as_secs());
+            dst.put_u32(timeout.subsec_nanos());
+        }
+        None => dst.put_u8(0),
+    }
+    put_metadata(dst, &request.metadata);
+    dst.put_slice(&request.value);
+}
+
+fn get_request(mut src: Bytes) -> io::Result<RequestFrame<Bytes>> {
+    let timeout = match get_u8(&mut src)? {
+        0 => None,
+        _ => {
+            let secs = get_u64(&mut src)?;
+            let nanos = get_u32(&mut src)?;
+            // `Duration::new` carries extra nanoseconds into the seconds and panics if they
+            // overflow
+            if nanos >= 1_000_000_000 {
+                return Err(invalid("timeout nanoseconds out of range"));
+            }
+            Some(Duration::new(secs, nanos))
+        }
+    };
+    let metadata = get_metadata(&mut src)?;
+    Ok(RequestFrame {
+        timeout,
+        metadata,
+        value: src,
+    })
+}
+
+fn put_response(dst: &mut BytesMut, response: ResponseFrame<Bytes>) {
+    put_metadata(dst, &response.metadata);
+    match response.result {
+        Ok(value) => {
+            dst.put_u8(0);
+            dst.put_slice(&value);
+        }
+        Err(e) => {
+            dst.put_u8(1);
+            dst.put_u8(kind_to_u8(e.kind()));
+            put_str(dst, e.message());
+        }
+    }
+}
+
+fn get_response(mut src: Bytes) -> io::Result<ResponseFrame<Bytes>> {
+    let metadata = get_metadata(&mut src)?;
+    let result = match get_u8(&mut src)? {
+        0 => Ok(src),
+        _ => {
+            let kind = kind_from_u8(get_u8(&mut src)?)?;
+            Err(RemoteError::new(kind, get_str(&mut src)?))
+        }
+    };
+    Ok(ResponseFrame::from(result).with_metadata(metadata))
+}
+
+fn put_metadata(dst: &mut BytesMut, metadata: &Metadata) {
+    dst.put_u32(metadata.len() as u32);
+    for (key, value) in metadata.iter() {
+        put_str(dst, key);
+        put_str(dst, value);
+    }
+}
+
+fn get_metadata(src: &mut Bytes) -> io::Result<Metadata> {
+    let len = get_u32(src)?;
+    (0..len)
+        .map(|_| Ok((get_str(src)?, get_str(src)?)))
+        .collect()
+}
+
+fn put_str(dst: &mut BytesMut, s: &str) {
+    dst.put_u32(s.len() as u32);
+    dst.put_slice(s.as_bytes());
+}
+
+fn get_str(src: &mut Bytes) -> io::Result<String> {
+    let len = get_u32(src)? as usize;
+    let bytes = take(src, len)?;
+    String::from_utf8(bytes.to_vec()).map_err(|e| invalid(e.to_string()))
+}
+
+fn kind_to_u8(kind: RemoteErrorKind) -> u8 {
+    match kind {
+        RemoteErrorKind::Service => 0,
+        RemoteErrorKind::DeadlineExceeded => 1,
+        RemoteErrorKind::InvalidRequest => 2,
+        RemoteErrorKind::Unavailable => 3,
+        RemoteErrorKind::Internal => 4,
+        RemoteErrorKind::Overloaded => 5,
+        RemoteErrorKind::PermissionDenied => 6,
+    }
+}
+
+fn kind_from_u8(kind: u8) -> io::Result<RemoteErrorKind> {
+    match kind {
+        0 => Ok(RemoteErrorKind::Service),
+        1 => Ok(RemoteErrorKind::DeadlineExceeded),
+        2 => Ok(RemoteErrorKind::InvalidRequest),
+        3 => Ok(RemoteErrorKind::Unavailable),
+        4 => Ok(RemoteErrorKind::Internal),
+        5 => Ok(RemoteErrorKind::Overloaded),
+        6 => Ok(RemoteErrorKind::PermissionDenied),
+        other => Err(invalid(format!("unknown error kind {other}"))),
+    }
+}
+
+// `Buf`'s getters panic on short input, so lengths are checked before anything is read
+fn take(src: &mut Bytes, len: usize) -> io::Result<Bytes> {
+    if src.len() < len {
+        return Err(invalid("message is too short"));
+    }
+    Ok(src.split_to(len))
+}
+
+fn get_u8(src: &mut Bytes) -> io::Result<u8> {
+    Ok(take(src, 1)?[0])
+}
+
+fn get_u32(src: &mut Bytes) -> io::Result<u32> {
+    let bytes = take(src, 4)?;
+    Ok(u32::from_be_bytes(
+        bytes[..].try_into().expect("length checked"),
+    ))
+}
+
+fn get_u64(src: &mut Bytes) -> io::Result<u64> {
+    let bytes = take(src, 8)?;
+    Ok(u64::from_be_bytes(
+        bytes[..].try_into().expect("length checked"),
+    ))
+}
+
+fn invalid(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip<T: RawFrame>(message: T) -> T {
+        let mut dst = BytesMut::new();
+        message.encode(&mut dst);
+        T::decode(dst.freeze()).unwrap()
+    }
+
+    #[test]
+    fn requests_keep_their_timeout_metadata_and_value() {
+        let request = RequestFrame::new(Bytes::from("ping"))
+            .with_metadata(Metadata::new().with("request-id", "1"));
+        let request = RequestFrame {
+            timeout: Some(Duration::from_millis(1500)),
+            ..request
+        };
+        let PipelineClientMessage::Call(request) = round_trip(PipelineClientMessage::Call(request))
+        else {
+            panic!("expected a call");
+        };
+        assert_eq!(request.timeout, Some(Duration::from_millis(1500)));
+        assert_eq!(request.metadata.get("request-id"), Some("1"));
+        assert_eq!(request.value, "ping");
+    }
+
+    #[test]
+    fn responses_keep_their_error() {
+        let error = RemoteError::new(RemoteErrorKind::PermissionDenied, "not allowed");
+        let PipelineServerMessage::Response(response) = round_trip(
+            PipelineServerMessage::<Bytes>::Response(ResponseFrame::err(error.clone())),
+        ) else {
+            panic!("expected a response");
+        };
+        assert_eq!(response.into_result().unwrap_err(), error);
+    }
+
+    #[test]
+    fn truncated_messages_are_invalid_data() {
+        let request =
+            RequestFrame::new(Bytes::from("ping")).with_metadata(Metadata::new().with("a", "b"));
+        let mut dst = BytesMut::new();
+        PipelineClientMessage::Call(request).encode(&mut dst);
+        let e = PipelineClientMessage::<Bytes>::decode(dst.freeze().slice(..3)).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn timeouts_with_too_many_nanoseconds_are_invalid_data() {
+        let mut src = BytesMut::new();
+        src.put_u8(CALL);
+        src.put_u8(1);
+        src.put_u64(u64::MAX);
+        src.put_u32(1_000_000_000);
+        let e = PipelineClientMessage::<Bytes>::decode(src.freeze()).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -1,19 +1,62 @@
 use std::fmt::{Debug, Formatter};
+use std::time::Instant;
 
 use background_service::ServiceContext;
 
+use crate::{Extensions, Metadata, Notifier, Trailers};
+
 #[derive(Clone)]
 pub struct Request<T> {
     pub context: ServiceContext,
+    /// Point in time after which the client is no longer waiting for a response.
+    pub deadline: Option<Instant>,
+    /// Metadata the client sent with the request.
+    pub metadata: Metadata,
+    /// Trailing metadata sent back with the response. Trailers aren't sent for streaming
+    /// responses.
+    pub trailers: Trailers,
+    /// Data attached by layers in front of the handler. This is never sent over the wire.
+    pub extensions: Extensions,
     pub value: T,
 }
 
+impl<T> Request<T> {
+    /// Sends notifications to the client. Only available on multiplexed connections, and only
+    /// when `R` is the connection's response type.
+    pub fn notifier<R>(&self) -> Option<&Notifier<R>>
+    where
+        R: Send + 'static,
+    {
+        self.extensions.get()
+    }
+
+    /// Identifies the request on its connection. Casts on multiplexed connections don't have an
+    /// id.
+    pub fn id(&self) -> Option<RequestId> {
+        self.extensions.get().copied()
+    }
+}
+
+/// Identifies a request on its connection so it can be matched up in logs. The server adds it to
+/// each request's extensions.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum RequestId {
+    /// Position of the request on a pipelined connection, counting from zero. Casts are counted
+    /// too.
+    Sequence(u64),
+    /// The tag the client sent with a call on a multiplexed connection.
+    #[cfg(feature = "multiplex")]
+    Tag(crate::Tag),
+}
+
 impl<T> Debug for Request<T>
 where
     T: Debug,
 {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("Request")
+            .field("deadline", &self.deadline)
+            .field("metadata", &self.metadata)
             .field("value", &self.value)
             .finish()
     }
@@ -12,7 +12,7 @@ use tokio::sync::mpsc::{self};
 use tokio::sync::oneshot;
 use tower::{BoxError, Service};
 
-use crate::Request;
+use crate::{ConnectionInfo, Request};
 
 #[derive(Debug)]
 pub struct MakeServiceFn<F, S, R>
@@ -35,7 +35,7 @@ where
     }
 }
 
-impl<F, S, R> Service<()> for MakeServiceFn<F, S, R>
+impl<F, S, R> Service<ConnectionInfo> for MakeServiceFn<F, S, R>
 where
     F: Fn() -> S,
     S: Service<R>,
@@ -48,11 +48,51 @@ where
         Ok(()).into()
     }
 
-    fn call(&mut self, _req: ()) -> Self::Future {
+    fn call(&mut self, _req: ConnectionInfo) -> Self::Future {
         future::ready(Ok((self.f)()))
     }
 }
 
+#[derive(Debug)]
+pub struct MakeServiceWithInfoFn<F, S, R>
+where
+    F: Fn(ConnectionInfo) -> S,
+    S: tower::Service<R>,
+{
+    f: F,
+    _phantom: PhantomData<R>,
+}
+
+/// Like [`make_service_fn`], but the function receives the info for each accepted connection.
+pub fn make_service_fn_with_info<F, S, R>(f: F) -> MakeServiceWithInfoFn<F, S, R>
+where
+    F: Fn(ConnectionInfo) -> S,
+    S: Service<R>,
+{
+    MakeServiceWithInfoFn {
+        f,
+        _phantom: Default::default(),
+    }
+}
+
+impl<F, S, R> Service<ConnectionInfo> for MakeServiceWithInfoFn<F, S, R>
+where
+    F: Fn(ConnectionInfo) -> S,
+    S: Service<R>,
+{
+    type Error = Infallible;
+    type Response = S;
+    type Future = future::Ready<Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Ok(()).into()
+    }
+
+    fn call(&mut self, info: ConnectionInfo) -> Self::Future {
+        future::ready(Ok((self.f)(info)))
+    }
+}
+
 pub fn channel<Req>() -> (ServiceChannel<Req>, mpsc::UnboundedReceiver<Request<Req>>) {
     let (tx, rx) = mpsc::unbounded_channel();
     (ServiceChannel { tx }, rx)
@@ -62,7 +102,7 @@ pub struct ServiceChannel<Req> {
     tx: mpsc::UnboundedSender<Request<Req>>,
 }
 
-impl<Req> tower::Service<()> for ServiceChannel<Req> {
+impl<Req> tower::Service<ConnectionInfo> for ServiceChannel<Req> {
     type Error = BoxError;
     type Response = ServiceSender<Req>;
     type Future = future::Ready<Result<Self::Response, Self::Error>>;
@@ -71,7 +111,7 @@ impl<Req> tower::Service<()> for ServiceChannel<Req> {
         Ok(()).into()
     }
 
-    fn call(&mut self, _req: ()) -> Self::Future {
+    fn call(&mut self, _req: ConnectionInfo) -> Self::Future {
         let sender = ServiceSender {
             tx: self.tx.clone(),
         };
@@ -126,7 +166,7 @@ impl<Req, Res> RequestHandlerStreamFactory<Req, Res> {
     }
 }
 
-impl<Req, Res> tower::Service<()> for RequestHandlerStreamFactory<Req, Res> {
+impl<Req, Res> tower::Service<ConnectionInfo> for RequestHandlerStreamFactory<Req, Res> {
     type Error = BoxError;
     type Response = RequestHandlerStream<Req, Res>;
     type Future = future::Ready<Result<Self::Response, Self::Error>>;
@@ -135,7 +175,7 @@ impl<Req, Res> tower::Service<()> for RequestHandlerStreamFactory<Req, Res> {
         Ok(()).into()
     }
 
-    fn call(&mut self, _req: ()) -> Self::Future {
+    fn call(&mut self, _req: ConnectionInfo) -> Self::Future {
         let stream = RequestHandlerStream {
             _phantom: Default::default(),
             request_tx: self.request_tx.clone(),
@@ -202,14 +242,33 @@ pub trait MakeHandler<Req>
 where
     Self: tower::Service<Req> + Default + Sized,
 {
-    fn make(_: ()) -> impl Future<Output = Result<Self, Infallible>> + Send;
+    fn make(_: ConnectionInfo) -> impl Future<Output = Result<Self, Infallible>> + Send;
 }
 
 impl<S, Req> MakeHandler<Req> for S
 where
     S: tower::Service<Req> + Default,
 {
-    async fn make(_: ()) -> Result<Self, Infallible> {
+    async fn make(_: ConnectionInfo) -> Result<Self, Infallible> {
         Ok(Self::default())
     }
 }
+
+/// Like [`MakeHandler`], but the handler is created from the info for each accepted connection.
+pub trait MakeHandlerWithInfo<Req>
+where
+    Self: tower::Service<Req> + From<ConnectionInfo> + Sized,
+{
+    fn make_with_info(
+        info: ConnectionInfo,
+    ) -> impl Future<Output = Result<Self, Infallible>> + Send;
+}
+
+impl<S, Req> MakeHandlerWithInfo<Req> for S
+where
+    S: tower::Service<Req> + From<ConnectionInfo>,
+{
+    async fn make_with_info(info: ConnectionInfo) -> Result<Self, Infallible> {
+        Ok(Self::from(info))
+    }
+}
@@ -5,13 +5,14 @@ use std::hash::Hash;
 use std::marker::PhantomData;
 use std::pin::Pin;
 use std::task::{Context, Poll};
+use std::time::Instant;
 
 use background_service::ServiceContext;
 use futures::Future;
 use matchit::{InsertError, MatchError, Params, Router};
 use tower::{BoxError, Service, ServiceExt};
 
-use crate::Request;
+use crate::{Extensions, Metadata, Notifier, RemoteError, RemoteErrorKind, Request, Trailers};
 
 pub trait RouteKey {
     type Key;
@@ -33,19 +34,21 @@ impl<T> RouteKey for Keyed<T> {
 #[cfg(feature = "codec")]
 pub fn routed_codec<Req, Res>(
     codec: transport_async::codec::Codec,
-) -> transport_async::codec::SerdeCodec<RoutedRequest<Req, Unkeyed>, Res> {
-    use transport_async::codec::SerdeCodec;
-
-    SerdeCodec::<RoutedRequest<Req, Unkeyed>, Res>::new(codec)
+) -> transport_async::codec::SerdeCodec<
+    crate::PipelineClientMessage<RoutedRequest<Req, Unkeyed>>,
+    crate::PipelineServerMessage<Res>,
+> {
+    crate::pipeline_codec::<RoutedRequest<Req, Unkeyed>, Res>(codec)
 }
 
 #[cfg(feature = "codec")]
 pub fn keyed_codec<Req, Res, K>(
     codec: transport_async::codec::Codec,
-) -> transport_async::codec::SerdeCodec<RoutedRequest<Req, Keyed<K>>, Res> {
-    use transport_async::codec::SerdeCodec;
-
-    SerdeCodec::<RoutedRequest<Req, Keyed<K>>, Res>::new(codec)
+) -> transport_async::codec::SerdeCodec<
+    crate::PipelineClientMessage<RoutedRequest<Req, Keyed<K>>>,
+    crate::PipelineServerMessage<Res>,
+> {
+    crate::pipeline_codec::<RoutedRequest<Req, Keyed<K>>, Res>(codec)
 }
 
 #[derive(Clone)]
@@ -146,7 +149,7 @@ where
 impl<Req, S, K> Service<Request<RoutedRequest<Req, K>>> for RouteService<Req, S, K>
 where
     S: Service<RouteMatch<Req, K>>,
-    S::Error: Debug,
+    S::Error: Into<RemoteError> + Debug,
     S::Future: Send + 'static,
     S::Response: Send + 'static,
     K: RouteKey,
@@ -181,19 +184,23 @@ where
         let router = match self.routers.get(&req.value.key) {
             Some(router) => router,
             None => {
-                return Box::pin(future::ready(Err("No router found".into())));
+                return Box::pin(future::ready(Err(no_route("no router found"))));
             }
         };
         let svc_index = match router.at(&req.value.route) {
             Ok(index) => index,
-            Err(e) => {
-                return Box::pin(future::ready(Err(e.into())));
+            Err(_) => {
+                return Box::pin(future::ready(Err(no_route("no route found"))));
             }
         };
 
         self.not_ready.push_back(*svc_index.value);
         let inner_rs = self.services[*svc_index.value].call(RouteMatch {
             context: req.context,
+            deadline: req.deadline,
+            metadata: req.metadata,
+            trailers: req.trailers,
+            extensions: req.extensions,
             route: req.value.route,
             key: req.value.key,
             router: router.clone(),
@@ -201,11 +208,16 @@ where
         });
         Box::pin(async move {
             let res = inner_rs.await;
-            Ok(res.map_err(|e| format!("{e:?}"))?)
+            // Boxed as a `RemoteError` so the server sends the route's error as is
+            res.map_err(|e| Into::<RemoteError>::into(e).into())
         })
     }
 }
 
+fn no_route(message: &str) -> BoxError {
+    RemoteError::new(RemoteErrorKind::InvalidRequest, message).into()
+}
+
 #[derive(Clone, Debug)]
 #[cfg_attr(feature = "codec", derive(serde::Serialize, serde::Deserialize))]
 pub struct RoutedRequest<T, K: RouteKey> {
@@ -216,6 +228,10 @@ pub struct RoutedRequest<T, K: RouteKey> {
 
 pub struct RouteMatch<T, K: RouteKey = Unkeyed> {
     pub context: ServiceContext,
+    pub deadline: Option<Instant>,
+    pub metadata: Metadata,
+    pub trailers: Trailers,
+    pub extensions: Extensions,
     pub route: String,
     pub key: K::Key,
     pub value: T,
@@ -223,6 +239,15 @@ pub struct RouteMatch<T, K: RouteKey = Unkeyed> {
 }
 
 impl<T, K: RouteKey> RouteMatch<T, K> {
+    /// Sends notifications to the client. Only available on multiplexed connections, and only
+    /// when `R` is the connection's response type.
+    pub fn notifier<R>(&self) -> Option<&Notifier<R>>
+    where
+        R: Send + 'static,
+    {
+        self.extensions.get()
+    }
+
     pub fn params(&self) -> Result<Params, MatchError> {
         Ok(self.router.at(&self.route)?.params)
     }
@@ -0,0 +1,137 @@
+use std::fmt::Debug;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use background_service::ServiceContext;
+use futures::Stream;
+use futures_cancel::FutureExt;
+use tokio_stream::StreamExt;
+use tracing::{error, warn};
+
+use crate::Backoff;
+
+type IsFatal<E> = Box<dyn Fn(&E) -> bool + Send + Sync>;
+
+#[derive(Debug, Default)]
+struct Counts {
+    total_failures: AtomicU64,
+    consecutive_failures: AtomicU32,
+}
+
+/// Number of times the server failed to accept a connection. Clones share the same counts, so
+/// this can be kept after the server starts running.
+#[derive(Clone, Debug, Default)]
+pub struct AcceptStats {
+    counts: Arc<Counts>,
+}
+
+impl AcceptStats {
+    pub fn total_failures(&self) -> u64 {
+        self.counts.total_failures.load(Ordering::Relaxed)
+    }
+
+    /// Failures since the last connection was accepted.
+    pub fn consecutive_failures(&self) -> u32 {
+        self.counts.consecutive_failures.load(Ordering::Relaxed)
+    }
+}
+
+/// Decides what the server does when accepting a connection fails.
+///
+/// By default, every error is logged and retried after a short delay so that transient failures,
+/// such as running out of file descriptors, don't stop the server.
+pub struct AcceptPolicy<E> {
+    backoff: Backoff,
+    is_fatal: Option<IsFatal<E>>,
+    stats: AcceptStats,
+}
+
+impl<E> Default for AcceptPolicy<E> {
+    fn default() -> Self {
+        Self {
+            backoff: Backoff::default()
+                .with_initial_delay(Duration::from_millis(5))
+                .with_max_delay(Duration::from_secs(1)),
+            is_fatal: None,
+            stats: AcceptStats::default(),
+        }
+    }
+}
+
+impl<E> AcceptPolicy<E> {
+    /// Sets the delay between retries. If the backoff has a maximum number of attempts, the
+    /// server stops after that many consecutive failures.
+    pub fn with_backoff(mut self, backoff: Backoff) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    /// Stops the server when `is_fatal` returns true for an accept error instead of retrying.
+    pub fn with_fatal_errors<F>(mut self, is_fatal: F) -> Self
+    where
+        F: Fn(&E) -> bool + Send + Sync + 'static,
+    {
+        self.is_fatal = Some(Box::new(is_fatal));
+        self
+    }
+
+    /// Returns a handle to the number of accept errors handled by this policy.
+    pub fn stats(&self) -> AcceptStats {
+        self.stats.clone()
+    }
+}
+
+impl<E: Debug> AcceptPolicy<E> {
+    /// Waits for the next connection, retrying accept errors according to the policy. Returns
+    /// `None` once the incoming stream ends or the server is shutting down.
+    pub(crate) async fn accept<S, I>(
+        &mut self,
+        incoming: &mut Pin<&mut S>,
+        context: &ServiceContext,
+    ) -> Result<Option<I>, E>
+    where
+        S: Stream<Item = Result<I, E>>,
+    {
+        let cancellation_token = context.cancellation_token();
+        loop {
+            let e = match incoming
+                .next()
+                .cancel_on_shutdown(&cancellation_token)
+                .await
+            {
+                Ok(Some(Ok(conn))) => {
+                    self.stats
+                        .counts
+                        .consecutive_failures
+                        .store(0, Ordering::Relaxed);
+                    return Ok(Some(conn));
+                }
+                Ok(Some(Err(e))) => e,
+                Ok(None) | Err(_) => return Ok(None),
+            };
+            let counts = &self.stats.counts;
+            let failures = counts.consecutive_failures.fetch_add(1, Ordering::Relaxed) + 1;
+            let total_failures = counts.total_failures.fetch_add(1, Ordering::Relaxed) + 1;
+            if self.is_fatal.as_ref().is_some_and(|is_fatal| is_fatal(&e))
+                || self.backoff.exhausted(failures)
+            {
+                error!("Failed to accept connection, stopping server: {e:?}");
+                return Err(e);
+            }
+            let delay = self.backoff.delay(failures);
+            warn!(
+                "Failed to accept connection ({total_failures} errors so far), retrying in \
+                 {delay:?}: {e:?}"
+            );
+            if tokio::time::sleep(delay)
+                .cancel_on_shutdown(&cancellation_token)
+                .await
+                .is_err()
+            {
+                return Ok(None);
+            }
+        }
+    }
+}
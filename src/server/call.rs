@@ -0,0 +1,69 @@
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures::stream::BoxStream;
+use futures::{Stream, StreamExt};
+
+use crate::{RemoteError, RemoteErrorKind};
+
+/// The items of a streaming request, ending once the client has finished sending.
+pub struct StreamingRequest<T> {
+    inner: BoxStream<'static, T>,
+}
+
+impl<T> StreamingRequest<T> {
+    pub fn new<S>(stream: S) -> Self
+    where
+        S: Stream<Item = T> + Send + 'static,
+    {
+        Self {
+            inner: stream.boxed(),
+        }
+    }
+}
+
+impl<T> Stream for StreamingRequest<T> {
+    type Item = T;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.inner.poll_next_unpin(cx)
+    }
+}
+
+pub enum Call<T> {
+    Single(T),
+    Stream(StreamingRequest<T>),
+}
+
+/// Converts an incoming call into the value a multiplex handler accepts.
+pub trait FromCall<T>: Sized {
+    fn from_call(call: Call<T>) -> Result<Self, RemoteError>;
+}
+
+impl<T> FromCall<T> for T {
+    fn from_call(call: Call<T>) -> Result<Self, RemoteError> {
+        match call {
+            Call::Single(value) => Ok(value),
+            Call::Stream(_) => Err(RemoteError::new(
+                RemoteErrorKind::InvalidRequest,
+                "handler does not accept streaming requests",
+            )),
+        }
+    }
+}
+
+impl<T> FromCall<T> for StreamingRequest<T>
+where
+    T: Send + 'static,
+{
+    fn from_call(call: Call<T>) -> Result<Self, RemoteError> {
+        match call {
+            // Single values are treated as a stream with one item so streaming handlers can
+            // still be called normally
+            Call::Single(value) => Ok(StreamingRequest::new(futures::stream::once(
+                futures::future::ready(value),
+            ))),
+            Call::Stream(stream) => Ok(stream),
+        }
+    }
+}
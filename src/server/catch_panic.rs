@@ -0,0 +1,136 @@
+use std::any::Any;
+use std::future::Future;
+use std::panic::{self, AssertUnwindSafe};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures::FutureExt;
+use tower::{Layer, Service};
+use tracing::error;
+
+use crate::{RemoteError, RemoteErrorKind, Request, RequestId};
+
+/// Catches panics in a handler so they only fail the request that caused them. The client gets
+/// a [`RemoteErrorKind::Internal`] error and the connection keeps serving other requests.
+///
+/// Panics are logged with the request's [`RequestId`]. Applied to the services passed to a
+/// router, they're logged with the matched route as well. Panics in a multiplexed handler's
+/// response stream don't need this layer, they always end just that stream.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CatchPanicLayer;
+
+impl CatchPanicLayer {
+    pub fn new() -> Self {
+        Self
+    }
+}
+
+impl<S> Layer<S> for CatchPanicLayer {
+    type Service = CatchPanic<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        CatchPanic { inner }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct CatchPanic<S> {
+    inner: S,
+}
+
+impl<S> CatchPanic<S> {
+    pub fn new(inner: S) -> Self {
+        Self { inner }
+    }
+
+    fn call_caught<R>(
+        &mut self,
+        req: R,
+        id: Option<RequestId>,
+        route: Option<String>,
+    ) -> Pin<Box<dyn Future<Output = Result<S::Response, RemoteError>> + Send>>
+    where
+        S: Service<R>,
+        S::Error: Into<RemoteError>,
+        S::Future: Send + 'static,
+    {
+        // Handlers can panic before returning their future as well as while it's polled
+        let res = panic::catch_unwind(AssertUnwindSafe(|| self.inner.call(req)));
+        Box::pin(async move {
+            let res = match res {
+                Ok(res) => AssertUnwindSafe(res).catch_unwind().await,
+                Err(payload) => Err(payload),
+            };
+            match res {
+                Ok(res) => res.map_err(Into::into),
+                Err(payload) => {
+                    error!(
+                        request = ?id,
+                        route = route.as_deref(),
+                        "Handler panicked: {}",
+                        panic_message(payload)
+                    );
+                    // The panic message may contain internal details so it isn't sent to the
+                    // client
+                    Err(RemoteError::new(
+                        RemoteErrorKind::Internal,
+                        "internal error",
+                    ))
+                }
+            }
+        })
+    }
+}
+
+impl<S, T> Service<Request<T>> for CatchPanic<S>
+where
+    S: Service<Request<T>>,
+    S::Error: Into<RemoteError>,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = RemoteError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, req: Request<T>) -> Self::Future {
+        let id = req.id();
+        self.call_caught(req, id, None)
+    }
+}
+
+#[cfg(feature = "router")]
+impl<S, T, K> Service<crate::RouteMatch<T, K>> for CatchPanic<S>
+where
+    S: Service<crate::RouteMatch<T, K>>,
+    S::Error: Into<RemoteError>,
+    S::Future: Send + 'static,
+    K: crate::RouteKey,
+{
+    type Response = S::Response;
+    type Error = RemoteError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, req: crate::RouteMatch<T, K>) -> Self::Future {
+        let id = req.extensions.get().copied();
+        let route = req.route.clone();
+        self.call_caught(req, id, Some(route))
+    }
+}
+
+pub(crate) fn panic_message(payload: Box<dyn Any + Send>) -> String {
+    match payload.downcast::<String>() {
+        Ok(message) => *message,
+        Err(payload) => match payload.downcast::<&'static str>() {
+            Ok(message) => message.to_string(),
+            Err(_) => "unknown panic".to_owned(),
+        },
+    }
+}
@@ -0,0 +1,112 @@
+use std::fmt::Debug;
+use std::future::Future;
+use std::pin::pin;
+use std::time::Duration;
+
+use background_service::error::BoxedError;
+use background_service::ServiceContext;
+use futures::future::{self, Either};
+use futures::{Sink, SinkExt};
+use futures_cancel::FutureExt;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info, warn};
+
+use crate::{ConnectionInfo, DisconnectReason, Hooks, RegisteredConnection};
+
+#[cfg(feature = "multiplex")]
+mod multiplex;
+#[cfg(feature = "multiplex")]
+pub(crate) use multiplex::*;
+mod pipeline;
+pub(crate) use pipeline::*;
+
+#[derive(Debug)]
+pub(crate) enum ConnectionError<S, R, E> {
+    BrokenTransportSend(S),
+    BrokenTransportRecv(R),
+    Service(E),
+    /// The client stopped answering keepalive pings.
+    Unresponsive,
+}
+
+pub(crate) trait Drain {
+    /// Stops accepting new requests. The connection closes once the in-flight requests finish.
+    fn drain(&mut self);
+}
+
+/// Reports why the connection ended to the disconnect hook. Service errors are returned so they
+/// fail the connection's task, while transport errors are only logged since the client
+/// disconnecting can cause them.
+pub(crate) fn finish<S, R, E>(
+    res: Option<Result<(), ConnectionError<S, R, E>>>,
+    context: &ServiceContext,
+    connection: &RegisteredConnection,
+    info: &ConnectionInfo,
+    hooks: &Hooks,
+) -> Result<(), BoxedError>
+where
+    S: Debug,
+    R: Debug,
+    E: Debug,
+{
+    let reason = match res {
+        Some(Ok(())) | None if context.cancellation_token().is_cancelled() => {
+            DisconnectReason::Shutdown
+        }
+        Some(Ok(())) | None if connection.close.is_cancelled() => DisconnectReason::Evicted,
+        Some(Ok(())) | None => DisconnectReason::Closed,
+        Some(Err(ConnectionError::Unresponsive)) => DisconnectReason::Unresponsive,
+        Some(Err(ConnectionError::Service(e))) => DisconnectReason::Service(format!("{e:?}")),
+        Some(Err(e)) => {
+            info!("Transport failure: {e:?}");
+            DisconnectReason::Transport(format!("{e:?}"))
+        }
+    };
+    hooks.disconnected(info, &reason);
+    match reason {
+        DisconnectReason::Service(e) => Err(e)?,
+        _ => Ok(()),
+    }
+}
+
+/// Sends the client a message saying the server is busy, then closes the connection.
+pub(crate) async fn reject<I, M>(transport: I, busy: M)
+where
+    I: Sink<M>,
+    I::Error: Debug,
+{
+    let mut transport = pin!(transport);
+    if let Err(e) = transport.send(busy).await {
+        debug!("Failed to reject connection: {e:?}");
+        return;
+    }
+    let _ = transport.close().await;
+}
+
+/// Runs the connection until it finishes or `close` is cancelled. Without a drain timeout, the
+/// connection is dropped as soon as it's closed. Otherwise, in-flight requests are given up to the
+/// timeout to finish. Returns `None` if the connection was cancelled.
+pub(crate) async fn serve<C>(
+    mut connection: C,
+    close: &CancellationToken,
+    drain_timeout: Option<Duration>,
+) -> Option<C::Output>
+where
+    C: Future + Drain + Unpin,
+{
+    let Some(drain_timeout) = drain_timeout else {
+        return connection.cancel_on_shutdown(close).await.ok();
+    };
+    if let Either::Left((res, _)) = future::select(&mut connection, pin!(close.cancelled())).await {
+        return Some(res);
+    }
+
+    connection.drain();
+    match tokio::time::timeout(drain_timeout, connection).await {
+        Ok(res) => Some(res),
+        Err(_) => {
+            warn!("Connection did not drain within {drain_timeout:?}, cancelling it");
+            None
+        }
+    }
+}
@@ -0,0 +1,843 @@
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::panic::{self, AssertUnwindSafe};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+use std::time::Instant;
+
+use futures::future::{AbortHandle, Abortable, Aborted};
+use futures::stream::{self, FuturesUnordered, SelectAll};
+use futures::task::AtomicWaker;
+use futures::{Sink, Stream, TryStream};
+use pin_project_lite::pin_project;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::{mpsc, AcquireError, OwnedSemaphorePermit};
+use tokio::time::Sleep;
+use tracing::{debug, error, info_span};
+
+use super::{ConnectionError, Drain};
+use crate::{
+    panic_message, Call, ClientFrame, ClientMessage, FromCall, Keepalive, KeepaliveEvent,
+    KeepaliveTimer, OnRequestLimit, RemoteError, RemoteErrorKind, Reply, RequestFrame,
+    RequestLimits, ResponseFrame, ResponseStream, ServerFrame, ServerMessage, StreamingRequest,
+    Tag, Tagged, STREAM_WINDOW,
+};
+
+type AcquireFuture =
+    Pin<Box<dyn Future<Output = Result<OwnedSemaphorePermit, AcquireError>> + Send>>;
+
+pin_project! {
+    struct InFlight<F> {
+        tag: Tag,
+        deadline: Option<Instant>,
+        // Slot in the server-wide request limit, passed on to the response stream if there is one
+        permit: Option<OwnedSemaphorePermit>,
+        #[pin]
+        future: Abortable<F>,
+    }
+}
+
+impl<F> Future for InFlight<F>
+where
+    F: Future,
+{
+    type Output = (
+        Tag,
+        Option<Instant>,
+        Option<OwnedSemaphorePermit>,
+        Result<F::Output, Aborted>,
+    );
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+        let res = ready!(this.future.poll(cx));
+        Poll::Ready((*this.tag, *this.deadline, this.permit.take(), res))
+    }
+}
+
+pin_project! {
+    struct InFlightCast<F> {
+        permit: Option<OwnedSemaphorePermit>,
+        #[pin]
+        future: F,
+    }
+}
+
+impl<F> Future for InFlightCast<F>
+where
+    F: Future,
+{
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+        let res = ready!(this.future.poll(cx));
+        this.permit.take();
+        Poll::Ready(res)
+    }
+}
+
+// Items the client has room for on a streaming response. The connection adds to it as credit
+// arrives, which wakes the stream if it was waiting.
+struct StreamCredit {
+    available: AtomicU32,
+    waker: AtomicWaker,
+}
+
+impl StreamCredit {
+    fn new() -> Self {
+        Self {
+            available: AtomicU32::new(STREAM_WINDOW),
+            waker: AtomicWaker::new(),
+        }
+    }
+
+    fn grant(&self, n: u32) {
+        let _ = self
+            .available
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |available| {
+                Some(available.saturating_add(n))
+            });
+        self.waker.wake();
+    }
+
+    fn poll_available(&self, cx: &mut Context<'_>) -> Poll<()> {
+        if self.available.load(Ordering::Acquire) > 0 {
+            return Poll::Ready(());
+        }
+        self.waker.register(cx.waker());
+        // Credit may have been granted before the waker was registered
+        if self.available.load(Ordering::Acquire) > 0 {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+
+    fn consume(&self) {
+        self.available.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+pin_project! {
+    struct InFlightStream<T> {
+        tag: Tag,
+        permit: Option<OwnedSemaphorePermit>,
+        credit: Arc<StreamCredit>,
+        #[pin]
+        deadline: Option<Sleep>,
+        #[pin]
+        stream: stream::Abortable<ResponseStream<T>>,
+        finished: bool,
+    }
+}
+
+impl<T> Stream for InFlightStream<T> {
+    type Item = (Tag, ServerFrame<T>);
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut this = self.project();
+        if *this.finished {
+            return Poll::Ready(None);
+        }
+        if let Some(deadline) = this.deadline.as_mut().as_pin_mut() {
+            if deadline.poll(cx).is_ready() {
+                *this.finished = true;
+                return Poll::Ready(Some((
+                    *this.tag,
+                    ServerFrame::Response(ResponseFrame::err(RemoteError::new(
+                        RemoteErrorKind::DeadlineExceeded,
+                        "deadline exceeded",
+                    ))),
+                )));
+            }
+        }
+        ready!(this.credit.poll_available(cx));
+        // Streams are polled by the connection rather than the handler, so a panic here would
+        // take down every request on the connection
+        let stream = this.stream.as_mut();
+        let next = match panic::catch_unwind(AssertUnwindSafe(|| stream.poll_next(cx))) {
+            Ok(next) => next,
+            Err(payload) => {
+                error!(tag = ?this.tag, "Response stream panicked: {}", panic_message(payload));
+                *this.finished = true;
+                this.permit.take();
+                // The panic message may contain internal details so it isn't sent to the client
+                return Poll::Ready(Some((
+                    *this.tag,
+                    ServerFrame::Response(ResponseFrame::err(RemoteError::new(
+                        RemoteErrorKind::Internal,
+                        "internal error",
+                    ))),
+                )));
+            }
+        };
+        match ready!(next) {
+            Some(item) => {
+                this.credit.consume();
+                Poll::Ready(Some((*this.tag, ServerFrame::Item(item.into()))))
+            }
+            None => {
+                *this.finished = true;
+                this.permit.take();
+                // The client already gave up on the stream so there's no need to end it
+                if this.stream.is_aborted() {
+                    Poll::Ready(None)
+                } else {
+                    Poll::Ready(Some((*this.tag, ServerFrame::End)))
+                }
+            }
+        }
+    }
+}
+
+// The items of a streaming request. Credit is sent back to the client as the handler consumes
+// them.
+struct Upload<T> {
+    tag: Tag,
+    rx: mpsc::Receiver<T>,
+    consumed: u32,
+    credit_tx: mpsc::UnboundedSender<(Tag, u32)>,
+}
+
+impl<T> Stream for Upload<T> {
+    type Item = T;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let item = ready!(self.rx.poll_recv(cx));
+        if item.is_some() {
+            self.consumed += 1;
+            if self.consumed >= STREAM_WINDOW / 2 {
+                let credit = (self.tag, self.consumed);
+                let _ = self.credit_tx.send(credit);
+                self.consumed = 0;
+            }
+        }
+        Poll::Ready(item)
+    }
+}
+
+type MultiplexConnectionError<I, Res, E> =
+    ConnectionError<<I as Sink<ServerMessage<Res>>>::Error, <I as TryStream>::Error, E>;
+
+/// Drives a single multiplexed connection, running requests concurrently and sending each
+/// response as soon as it's ready.
+pub(crate) struct MultiplexConnection<I, S, In, Req, Res>
+where
+    S: tower::Service<(Option<Tag>, RequestFrame<In>), Response = ResponseFrame<Reply<Res>>>,
+{
+    transport: Pin<Box<I>>,
+    service: S,
+    in_flight: FuturesUnordered<InFlight<S::Future>>,
+    streams: SelectAll<Pin<Box<InFlightStream<Res>>>>,
+    abort_handles: HashMap<Tag, AbortHandle>,
+    // Flow control for the response streams that are still running
+    credits: HashMap<Tag, Arc<StreamCredit>>,
+    // Senders for streaming requests that the client hasn't finished sending yet
+    uploads: HashMap<Tag, mpsc::Sender<Req>>,
+    // Credit for the client's uploads, sent by each upload as the handler consumes it
+    upload_credit_tx: mpsc::UnboundedSender<(Tag, u32)>,
+    upload_credit_rx: mpsc::UnboundedReceiver<(Tag, u32)>,
+    responses: VecDeque<ServerMessage<Res>>,
+    // One-way requests whose results are discarded
+    casts: FuturesUnordered<InFlightCast<S::Future>>,
+    notifications: mpsc::Receiver<Res>,
+    // New requests waiting to be started, oldest first. Casts don't have a tag since nothing is
+    // sent back for them.
+    pending: VecDeque<(Option<Tag>, RequestFrame<In>)>,
+    // New request that arrived while the queue was full. Reading stops until it fits.
+    held: Option<ClientMessage<Req>>,
+    limits: RequestLimits,
+    // Server-wide slot for the oldest pending request, either being waited on or already acquired.
+    // Each connection only waits for one slot at a time and the semaphore hands them out in the
+    // order they were asked for, so busy connections take turns with the others.
+    acquiring: Option<AcquireFuture>,
+    permit: Option<OwnedSemaphorePermit>,
+    keepalive: Option<KeepaliveTimer>,
+    read_closed: bool,
+    // Frames for requests that are already running are still read while draining so uploads and
+    // cancellations keep working
+    draining: bool,
+}
+
+// None of the fields are structurally pinned
+impl<I, S, In, Req, Res> Unpin for MultiplexConnection<I, S, In, Req, Res> where
+    S: tower::Service<(Option<Tag>, RequestFrame<In>), Response = ResponseFrame<Reply<Res>>>
+{
+}
+
+impl<I, S, In, Req, Res> MultiplexConnection<I, S, In, Req, Res>
+where
+    I: TryStream<Ok = ClientMessage<Req>> + Sink<ServerMessage<Res>>,
+    S: tower::Service<(Option<Tag>, RequestFrame<In>), Response = ResponseFrame<Reply<Res>>>,
+    In: FromCall<Req>,
+    Req: Send + 'static,
+    Res: 'static,
+{
+    pub(crate) fn new(
+        transport: I,
+        service: S,
+        notifications: mpsc::Receiver<Res>,
+        limits: RequestLimits,
+        keepalive: Keepalive,
+    ) -> Self {
+        let (upload_credit_tx, upload_credit_rx) = mpsc::unbounded_channel();
+        Self {
+            transport: Box::pin(transport),
+            service,
+            in_flight: FuturesUnordered::new(),
+            streams: SelectAll::new(),
+            abort_handles: HashMap::new(),
+            credits: HashMap::new(),
+            uploads: HashMap::new(),
+            upload_credit_tx,
+            upload_credit_rx,
+            responses: VecDeque::new(),
+            casts: FuturesUnordered::new(),
+            notifications,
+            pending: VecDeque::new(),
+            held: None,
+            limits,
+            acquiring: None,
+            permit: None,
+            keepalive: KeepaliveTimer::new(keepalive),
+            read_closed: false,
+            draining: false,
+        }
+    }
+
+    fn poll_send(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Result<(), MultiplexConnectionError<I, Res, S::Error>> {
+        while !self.responses.is_empty() {
+            match self.transport.as_mut().poll_ready(cx) {
+                Poll::Ready(Ok(())) => {
+                    let response = self.responses.pop_front().expect("response missing");
+                    self.transport
+                        .as_mut()
+                        .start_send(response)
+                        .map_err(ConnectionError::BrokenTransportSend)?;
+                }
+                Poll::Ready(Err(e)) => return Err(ConnectionError::BrokenTransportSend(e)),
+                Poll::Pending => break,
+            }
+        }
+        if let Poll::Ready(Err(e)) = self.transport.as_mut().poll_flush(cx) {
+            return Err(ConnectionError::BrokenTransportSend(e));
+        }
+        Ok(())
+    }
+
+    fn push_frame(&mut self, tag: Tag, value: ServerFrame<Res>) {
+        self.responses
+            .push_back(ServerMessage::Call(Tagged { tag, value }));
+    }
+
+    fn start_stream(
+        &mut self,
+        tag: Tag,
+        deadline: Option<Instant>,
+        permit: Option<OwnedSemaphorePermit>,
+        stream: ResponseStream<Res>,
+    ) {
+        let (abort_handle, registration) = AbortHandle::new_pair();
+        self.abort_handles.insert(tag, abort_handle);
+        let credit = Arc::new(StreamCredit::new());
+        self.credits.insert(tag, credit.clone());
+        self.streams.push(Box::pin(InFlightStream {
+            tag,
+            permit,
+            credit,
+            deadline: deadline.map(|deadline| tokio::time::sleep_until(deadline.into())),
+            stream: stream::Abortable::new(stream, registration),
+            finished: false,
+        }));
+    }
+
+    fn poll_in_flight(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Result<bool, MultiplexConnectionError<I, Res, S::Error>> {
+        let mut completed = false;
+        while let Poll::Ready(Some((tag, deadline, permit, res))) =
+            Pin::new(&mut self.in_flight).poll_next(cx)
+        {
+            self.abort_handles.remove(&tag);
+            match res {
+                Ok(Ok(response)) => {
+                    if !matches!(response.result, Ok(Reply::Stream(_))) {
+                        // Any remaining items can't affect the response anymore
+                        self.uploads.remove(&tag);
+                    }
+                    completed = true;
+                    let (result, metadata) = response.into_parts();
+                    let result = match result {
+                        Ok(Reply::Single(value)) => Ok(value),
+                        Ok(Reply::Stream(stream)) => {
+                            self.start_stream(tag, deadline, permit, stream);
+                            continue;
+                        }
+                        Err(e) => Err(e),
+                    };
+                    let response = ResponseFrame::from(result).with_metadata(metadata);
+                    self.push_frame(tag, ServerFrame::Response(response));
+                }
+                Ok(Err(e)) => return Err(ConnectionError::Service(e)),
+                // The client already gave up on the request so there's no need to respond
+                Err(Aborted) => {}
+            }
+        }
+        Ok(completed)
+    }
+
+    fn poll_casts(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Result<bool, MultiplexConnectionError<I, Res, S::Error>> {
+        let mut completed = false;
+        while let Poll::Ready(Some(res)) = Pin::new(&mut self.casts).poll_next(cx) {
+            completed = true;
+            // Nothing is sent back for casts, including any stream the handler returned
+            match res {
+                Ok(response) => {
+                    if let Err(e) = response.result {
+                        debug!("Cast failed: {e:?}");
+                    }
+                }
+                Err(e) => return Err(ConnectionError::Service(e)),
+            }
+        }
+        Ok(completed)
+    }
+
+    fn poll_streams(&mut self, cx: &mut Context<'_>) -> bool {
+        let mut progress = false;
+        while self.responses.len() < self.limits.send_buffer {
+            match Pin::new(&mut self.streams).poll_next(cx) {
+                Poll::Ready(Some((tag, value))) => {
+                    if !matches!(value, ServerFrame::Item(_)) {
+                        self.abort_handles.remove(&tag);
+                        self.credits.remove(&tag);
+                        self.uploads.remove(&tag);
+                    }
+                    self.push_frame(tag, value);
+                    progress = true;
+                }
+                Poll::Ready(None) | Poll::Pending => break,
+            }
+        }
+        progress
+    }
+
+    fn poll_notifications(&mut self, cx: &mut Context<'_>) -> bool {
+        let mut progress = false;
+        while self.responses.len() < self.limits.send_buffer {
+            match self.notifications.poll_recv(cx) {
+                Poll::Ready(Some(notification)) => {
+                    progress = true;
+                    self.responses
+                        .push_back(ServerMessage::Notification(notification));
+                }
+                Poll::Ready(None) | Poll::Pending => break,
+            }
+        }
+        progress
+    }
+
+    fn poll_upload_credit(&mut self, cx: &mut Context<'_>) -> bool {
+        let mut progress = false;
+        while let Poll::Ready(Some((tag, n))) = self.upload_credit_rx.poll_recv(cx) {
+            // No more credit is needed once the client has finished the upload
+            if self.uploads.contains_key(&tag) {
+                self.push_frame(tag, ServerFrame::Credit(n));
+                progress = true;
+            }
+        }
+        progress
+    }
+
+    fn running(&self) -> usize {
+        self.in_flight.len() + self.streams.len() + self.casts.len()
+    }
+
+    /// Checks whether there's room to run another request. Returns `Ready(false)` if the request
+    /// should be rejected.
+    fn poll_capacity(&mut self, cx: &mut Context<'_>) -> Poll<bool> {
+        let reject = self.limits.on_limit == OnRequestLimit::Reject;
+        if let Some(max_requests) = self.limits.per_connection {
+            if self.running() >= max_requests {
+                // Running requests wake the connection up when they finish
+                return if reject {
+                    Poll::Ready(false)
+                } else {
+                    Poll::Pending
+                };
+            }
+        }
+        let Some(semaphore) = &self.limits.global else {
+            return Poll::Ready(true);
+        };
+        if self.permit.is_some() {
+            return Poll::Ready(true);
+        }
+        if reject {
+            self.permit = semaphore.clone().try_acquire_owned().ok();
+            return Poll::Ready(self.permit.is_some());
+        }
+        let acquiring = self
+            .acquiring
+            .get_or_insert_with(|| Box::pin(semaphore.clone().acquire_owned()));
+        let permit = ready!(acquiring.as_mut().poll(cx));
+        self.acquiring = None;
+        // The semaphore is never closed
+        self.permit = permit.ok();
+        Poll::Ready(true)
+    }
+
+    fn queue_full(&self) -> bool {
+        self.pending.len() >= self.limits.queue_depth
+    }
+
+    // Reading stops once this many frames are waiting to be sent, which only happens when the
+    // client isn't reading its responses. Stream items and notifications stop at the send buffer,
+    // which leaves room for a response to each queued request on top of it.
+    fn max_unsent(&self) -> usize {
+        self.limits.send_buffer + self.limits.queue_depth
+    }
+
+    fn reject_overloaded(&mut self, tag: Option<Tag>) {
+        match tag {
+            Some(tag) => {
+                self.uploads.remove(&tag);
+                let error = RemoteError::new(RemoteErrorKind::Overloaded, "server is overloaded");
+                self.push_frame(tag, ServerFrame::Response(ResponseFrame::err(error)));
+            }
+            None => debug!("Dropping cast because the server is overloaded"),
+        }
+    }
+
+    // Fails a request whose client sent more items than it had credit for
+    fn reject_upload(&mut self, tag: Tag) {
+        debug!("Client sent more request items than allowed for {tag:?}");
+        self.uploads.remove(&tag);
+        if !self.remove_pending(tag) {
+            let Some(abort_handle) = self.abort_handles.remove(&tag) else {
+                return;
+            };
+            abort_handle.abort();
+            if let Some(credit) = self.credits.remove(&tag) {
+                credit.grant(u32::MAX);
+            }
+        }
+        let error = RemoteError::new(
+            RemoteErrorKind::InvalidRequest,
+            "too many request items in flight",
+        );
+        self.push_frame(tag, ServerFrame::Response(ResponseFrame::err(error)));
+    }
+
+    fn start_request(&mut self, tag: Tag, request: RequestFrame<Call<Req>>) {
+        if self.draining {
+            self.uploads.remove(&tag);
+            let error = RemoteError::new(RemoteErrorKind::Unavailable, "server is shutting down");
+            self.push_frame(tag, ServerFrame::Response(ResponseFrame::err(error)));
+            return;
+        }
+        if self.queue_full() {
+            self.reject_overloaded(Some(tag));
+            return;
+        }
+        match request.map(In::from_call).transpose() {
+            Ok(request) => self.pending.push_back((Some(tag), request)),
+            Err(e) => {
+                self.uploads.remove(&tag);
+                self.push_frame(tag, ServerFrame::Response(ResponseFrame::err(e)));
+            }
+        }
+    }
+
+    fn start_cast(&mut self, request: RequestFrame<Req>) {
+        if self.draining {
+            debug!("Dropping cast received while draining");
+            return;
+        }
+        if self.queue_full() {
+            self.reject_overloaded(None);
+            return;
+        }
+        match request
+            .map(|value| In::from_call(Call::Single(value)))
+            .transpose()
+        {
+            Ok(request) => self.pending.push_back((None, request)),
+            Err(e) => debug!("Dropping invalid cast: {e:?}"),
+        }
+    }
+
+    fn handle_frame(&mut self, tag: Tag, frame: ClientFrame<Req>) {
+        match frame {
+            ClientFrame::Request(request) => self.start_request(tag, request.map(Call::Single)),
+            ClientFrame::Open(request) => {
+                let (tx, rx) = mpsc::channel(STREAM_WINDOW as usize);
+                self.uploads.insert(tag, tx);
+                let stream = StreamingRequest::new(Upload {
+                    tag,
+                    rx,
+                    consumed: 0,
+                    credit_tx: self.upload_credit_tx.clone(),
+                });
+                self.start_request(tag, request.map(|()| Call::Stream(stream)));
+            }
+            ClientFrame::Item(item) => match self.uploads.get(&tag).map(|tx| tx.try_send(item)) {
+                // The handler may have stopped reading the stream early
+                Some(Ok(())) | Some(Err(TrySendError::Closed(_))) => {}
+                Some(Err(TrySendError::Full(_))) => self.reject_upload(tag),
+                None => debug!("Dropping request item for unknown tag {tag:?}"),
+            },
+            // Dropping the sender ends the handler's request stream
+            ClientFrame::End => {
+                self.uploads.remove(&tag);
+            }
+            ClientFrame::Cancel => {
+                self.uploads.remove(&tag);
+                self.remove_pending(tag);
+                if let Some(abort_handle) = self.abort_handles.remove(&tag) {
+                    abort_handle.abort();
+                }
+                // Lets an aborted stream that's waiting for credit see that it was aborted
+                if let Some(credit) = self.credits.remove(&tag) {
+                    credit.grant(u32::MAX);
+                }
+            }
+            ClientFrame::Credit(n) => match self.credits.get(&tag) {
+                Some(credit) => credit.grant(n),
+                None => debug!("Dropping credit for unknown tag {tag:?}"),
+            },
+        }
+    }
+
+    // Drops a request that hasn't started yet. Returns false if there isn't one for the tag.
+    fn remove_pending(&mut self, tag: Tag) -> bool {
+        match self
+            .pending
+            .iter()
+            .position(|(pending, _)| *pending == Some(tag))
+        {
+            Some(index) => {
+                self.pending.remove(index);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Starts pending requests in order for as long as there's capacity for them
+    fn start_pending(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Result<(), MultiplexConnectionError<I, Res, S::Error>> {
+        while !self.pending.is_empty() {
+            match self.poll_capacity(cx) {
+                Poll::Ready(true) => {}
+                Poll::Ready(false) => {
+                    let (tag, _) = self.pending.pop_front().expect("pending request missing");
+                    self.reject_overloaded(tag);
+                    continue;
+                }
+                Poll::Pending => return Ok(()),
+            }
+            match self.service.poll_ready(cx) {
+                Poll::Ready(Ok(())) => {}
+                Poll::Ready(Err(e)) => return Err(ConnectionError::Service(e)),
+                Poll::Pending => return Ok(()),
+            }
+            let (tag, request) = self.pending.pop_front().expect("pending request missing");
+            match tag {
+                Some(tag) => {
+                    let (abort_handle, registration) = AbortHandle::new_pair();
+                    self.abort_handles.insert(tag, abort_handle);
+                    let deadline = request.deadline();
+                    // Lets the handler's logs be matched up with the request
+                    let future = info_span!("call", tag = ?tag)
+                        .in_scope(|| self.service.call((Some(tag), request)));
+                    self.in_flight.push(InFlight {
+                        tag,
+                        deadline,
+                        permit: self.permit.take(),
+                        future: Abortable::new(future, registration),
+                    });
+                }
+                None => self.casts.push(InFlightCast {
+                    permit: self.permit.take(),
+                    future: self.service.call((None, request)),
+                }),
+            }
+        }
+        Ok(())
+    }
+
+    // Whether the message would add to the queue of requests waiting to start
+    fn is_new_request(message: &ClientMessage<Req>) -> bool {
+        matches!(
+            message,
+            ClientMessage::Call(Tagged {
+                value: ClientFrame::Request(_) | ClientFrame::Open(_),
+                ..
+            }) | ClientMessage::Cast(_)
+        )
+    }
+
+    fn handle_message(&mut self, message: ClientMessage<Req>) {
+        match message {
+            ClientMessage::Call(Tagged { tag, value }) => self.handle_frame(tag, value),
+            ClientMessage::Cast(request) => self.start_cast(request),
+            ClientMessage::Ping => self.responses.push_back(ServerMessage::Pong),
+            ClientMessage::Pong => {}
+        }
+    }
+
+    // Requests that don't fit in the queue are held instead of rejected under backpressure, which
+    // stops reading until they do. New requests are turned away right away while draining, so
+    // there's nothing to wait for then.
+    fn should_hold(&self, message: &ClientMessage<Req>) -> bool {
+        self.limits.on_limit == OnRequestLimit::Backpressure
+            && !self.draining
+            && self.queue_full()
+            && Self::is_new_request(message)
+    }
+
+    fn poll_recv(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Result<(), MultiplexConnectionError<I, Res, S::Error>> {
+        loop {
+            self.start_pending(cx)?;
+            if let Some(message) = self.held.take() {
+                if self.should_hold(&message) {
+                    // Starting a queued request wakes the connection back up
+                    self.held = Some(message);
+                    return Ok(());
+                }
+                self.handle_message(message);
+            }
+            if self.read_closed || self.responses.len() >= self.max_unsent() {
+                return Ok(());
+            }
+            let message = match self.transport.as_mut().try_poll_next(cx) {
+                Poll::Ready(Some(Ok(message))) => message,
+                Poll::Ready(Some(Err(e))) => return Err(ConnectionError::BrokenTransportRecv(e)),
+                Poll::Ready(None) => {
+                    self.read_closed = true;
+                    return Ok(());
+                }
+                Poll::Pending => return Ok(()),
+            };
+            if let Some(keepalive) = &mut self.keepalive {
+                keepalive.received();
+            }
+            if self.should_hold(&message) {
+                self.held = Some(message);
+            } else {
+                self.handle_message(message);
+            }
+        }
+    }
+
+    fn poll_keepalive(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Result<bool, MultiplexConnectionError<I, Res, S::Error>> {
+        let Some(keepalive) = &mut self.keepalive else {
+            return Ok(false);
+        };
+        if self.held.is_some() {
+            // The client's pongs can't be read until the held request fits, so they aren't
+            // counted as missed
+            keepalive.received();
+            return Ok(false);
+        }
+        let idle = self.pending.is_empty()
+            && self.in_flight.is_empty()
+            && self.casts.is_empty()
+            && self.streams.is_empty()
+            && self.responses.is_empty();
+        match keepalive.poll(cx, idle) {
+            Poll::Ready(KeepaliveEvent::Ping) => {
+                self.responses.push_back(ServerMessage::Ping);
+                Ok(true)
+            }
+            Poll::Ready(KeepaliveEvent::Unresponsive) => Err(ConnectionError::Unresponsive),
+            Poll::Ready(KeepaliveEvent::Idle) if !self.draining => {
+                debug!("Closing idle connection");
+                self.drain();
+                Ok(true)
+            }
+            Poll::Ready(KeepaliveEvent::Idle) | Poll::Pending => Ok(false),
+        }
+    }
+}
+
+impl<I, S, In, Req, Res> Future for MultiplexConnection<I, S, In, Req, Res>
+where
+    I: TryStream<Ok = ClientMessage<Req>> + Sink<ServerMessage<Res>>,
+    S: tower::Service<(Option<Tag>, RequestFrame<In>), Response = ResponseFrame<Reply<Res>>>,
+    In: FromCall<Req>,
+    Req: Send + 'static,
+    Res: 'static,
+{
+    type Output = Result<(), MultiplexConnectionError<I, Res, S::Error>>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        loop {
+            this.poll_recv(cx)?;
+            let mut progress = this.poll_in_flight(cx)?;
+            progress |= this.poll_casts(cx)?;
+            this.poll_send(cx)?;
+            // Streams and notifications are polled after sending so they only stop being polled
+            // when the transport is full, in which case we'll be woken up once it's ready again
+            let streamed = this.poll_streams(cx);
+            let notified = this.poll_notifications(cx);
+            let credited = this.poll_upload_credit(cx);
+            if streamed || notified || credited {
+                progress = true;
+                this.poll_send(cx)?;
+            }
+            progress |= this.poll_keepalive(cx)?;
+
+            if (this.read_closed || this.draining)
+                && this.pending.is_empty()
+                && this.held.is_none()
+                && this.in_flight.is_empty()
+                && this.casts.is_empty()
+                && this.streams.is_empty()
+                && this.responses.is_empty()
+            {
+                return this
+                    .transport
+                    .as_mut()
+                    .poll_close(cx)
+                    .map_err(ConnectionError::BrokenTransportSend);
+            }
+            // Requests that just completed may have freed up capacity in the service
+            if !progress {
+                return Poll::Pending;
+            }
+        }
+    }
+}
+
+impl<I, S, In, Req, Res> Drain for MultiplexConnection<I, S, In, Req, Res>
+where
+    S: tower::Service<(Option<Tag>, RequestFrame<In>), Response = ResponseFrame<Reply<Res>>>,
+{
+    fn drain(&mut self) {
+        if !self.draining {
+            self.draining = true;
+            self.responses.push_front(ServerMessage::GoAway);
+        }
+    }
+}
@@ -0,0 +1,264 @@
+use std::collections::VecDeque;
+use std::future::{self, Future};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures::future::Either;
+use futures::stream::{FuturesOrdered, FuturesUnordered};
+use futures::{Sink, Stream, TryStream};
+use tracing::debug;
+
+use super::{ConnectionError, Drain};
+use crate::{
+    Keepalive, KeepaliveEvent, KeepaliveTimer, PipelineClientMessage, PipelineServerMessage,
+    RemoteError, RemoteErrorKind, RequestFrame, ResponseFrame,
+};
+
+// Either a call to the handler or a rejection that has to wait its turn to be sent
+type ResponseFuture<F, Res, E> = Either<F, future::Ready<Result<ResponseFrame<Res>, E>>>;
+
+type PipelineConnectionError<I, Res, E> =
+    ConnectionError<<I as Sink<PipelineServerMessage<Res>>>::Error, <I as TryStream>::Error, E>;
+
+/// Drives a single pipelined connection, sending responses in the same order the requests were
+/// received.
+pub(crate) struct PipelineConnection<I, S, Req, Res>
+where
+    S: tower::Service<RequestFrame<Req>, Response = ResponseFrame<Res>>,
+{
+    transport: Pin<Box<I>>,
+    service: S,
+    in_flight: FuturesOrdered<ResponseFuture<S::Future, Res, S::Error>>,
+    // One-way requests whose results are discarded
+    casts: FuturesUnordered<S::Future>,
+    responses: VecDeque<PipelineServerMessage<Res>>,
+    next_request: Option<PipelineClientMessage<Req>>,
+    keepalive: Option<KeepaliveTimer>,
+    read_closed: bool,
+    draining: bool,
+}
+
+// None of the fields are structurally pinned
+impl<I, S, Req, Res> Unpin for PipelineConnection<I, S, Req, Res> where
+    S: tower::Service<RequestFrame<Req>, Response = ResponseFrame<Res>>
+{
+}
+
+impl<I, S, Req, Res> PipelineConnection<I, S, Req, Res>
+where
+    I: TryStream<Ok = PipelineClientMessage<Req>> + Sink<PipelineServerMessage<Res>>,
+    S: tower::Service<RequestFrame<Req>, Response = ResponseFrame<Res>>,
+{
+    pub(crate) fn new(transport: I, service: S, keepalive: Keepalive) -> Self {
+        Self {
+            transport: Box::pin(transport),
+            service,
+            in_flight: FuturesOrdered::new(),
+            casts: FuturesUnordered::new(),
+            responses: VecDeque::new(),
+            next_request: None,
+            keepalive: KeepaliveTimer::new(keepalive),
+            read_closed: false,
+            draining: false,
+        }
+    }
+
+    fn poll_send(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Result<(), PipelineConnectionError<I, Res, S::Error>> {
+        while !self.responses.is_empty() {
+            match self.transport.as_mut().poll_ready(cx) {
+                Poll::Ready(Ok(())) => {
+                    let response = self.responses.pop_front().expect("response missing");
+                    self.transport
+                        .as_mut()
+                        .start_send(response)
+                        .map_err(ConnectionError::BrokenTransportSend)?;
+                }
+                Poll::Ready(Err(e)) => return Err(ConnectionError::BrokenTransportSend(e)),
+                Poll::Pending => break,
+            }
+        }
+        if let Poll::Ready(Err(e)) = self.transport.as_mut().poll_flush(cx) {
+            return Err(ConnectionError::BrokenTransportSend(e));
+        }
+        Ok(())
+    }
+
+    fn poll_in_flight(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Result<bool, PipelineConnectionError<I, Res, S::Error>> {
+        let mut completed = false;
+        while let Poll::Ready(Some(res)) = Pin::new(&mut self.in_flight).poll_next(cx) {
+            completed = true;
+            let response = res.map_err(ConnectionError::Service)?;
+            self.responses
+                .push_back(PipelineServerMessage::Response(response));
+        }
+        Ok(completed)
+    }
+
+    fn poll_casts(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Result<bool, PipelineConnectionError<I, Res, S::Error>> {
+        let mut completed = false;
+        while let Poll::Ready(Some(res)) = Pin::new(&mut self.casts).poll_next(cx) {
+            completed = true;
+            let response = res.map_err(ConnectionError::Service)?;
+            if let Err(e) = response.result {
+                debug!("Cast failed: {e:?}");
+            }
+        }
+        Ok(completed)
+    }
+
+    fn poll_recv(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Result<(), PipelineConnectionError<I, Res, S::Error>> {
+        while !self.read_closed {
+            if let Some(request) = self.next_request.take() {
+                if self.draining {
+                    self.reject_unavailable(request);
+                    continue;
+                }
+                match self.service.poll_ready(cx) {
+                    Poll::Ready(Ok(())) => {}
+                    Poll::Ready(Err(e)) => return Err(ConnectionError::Service(e)),
+                    Poll::Pending => {
+                        self.next_request = Some(request);
+                        return Ok(());
+                    }
+                }
+                match request {
+                    PipelineClientMessage::Call(request) => self
+                        .in_flight
+                        .push_back(Either::Left(self.service.call(request))),
+                    PipelineClientMessage::Cast(request) => {
+                        self.casts.push(self.service.call(request))
+                    }
+                    // Answered as soon as they're read
+                    PipelineClientMessage::Ping | PipelineClientMessage::Pong => {}
+                }
+            }
+
+            let request = match self.transport.as_mut().try_poll_next(cx) {
+                Poll::Ready(Some(Ok(request))) => request,
+                Poll::Ready(Some(Err(e))) => return Err(ConnectionError::BrokenTransportRecv(e)),
+                Poll::Ready(None) => {
+                    self.read_closed = true;
+                    continue;
+                }
+                Poll::Pending => return Ok(()),
+            };
+            if let Some(keepalive) = &mut self.keepalive {
+                keepalive.received();
+            }
+            match request {
+                PipelineClientMessage::Ping => {
+                    self.responses.push_back(PipelineServerMessage::Pong)
+                }
+                PipelineClientMessage::Pong => {}
+                request => self.next_request = Some(request),
+            }
+        }
+        Ok(())
+    }
+
+    // Answers a request the client sent before it saw the GOAWAY. It hasn't been started, so the
+    // client can safely send it again on another connection.
+    fn reject_unavailable(&mut self, request: PipelineClientMessage<Req>) {
+        match request {
+            PipelineClientMessage::Call(_) => {
+                let error =
+                    RemoteError::new(RemoteErrorKind::Unavailable, "server is shutting down");
+                self.in_flight
+                    .push_back(Either::Right(future::ready(Ok(ResponseFrame::err(error)))));
+            }
+            PipelineClientMessage::Cast(_) => debug!("Dropping cast received while draining"),
+            PipelineClientMessage::Ping | PipelineClientMessage::Pong => {}
+        }
+    }
+
+    fn poll_keepalive(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Result<bool, PipelineConnectionError<I, Res, S::Error>> {
+        // The drain timeout takes over while draining
+        if self.draining {
+            return Ok(false);
+        }
+        let Some(keepalive) = &mut self.keepalive else {
+            return Ok(false);
+        };
+        let idle = self.next_request.is_none()
+            && self.in_flight.is_empty()
+            && self.casts.is_empty()
+            && self.responses.is_empty();
+        match keepalive.poll(cx, idle) {
+            Poll::Ready(KeepaliveEvent::Ping) => {
+                self.responses.push_back(PipelineServerMessage::Ping);
+                Ok(true)
+            }
+            Poll::Ready(KeepaliveEvent::Unresponsive) => Err(ConnectionError::Unresponsive),
+            Poll::Ready(KeepaliveEvent::Idle) => {
+                debug!("Closing idle connection");
+                self.drain();
+                Ok(true)
+            }
+            Poll::Pending => Ok(false),
+        }
+    }
+}
+
+impl<I, S, Req, Res> Future for PipelineConnection<I, S, Req, Res>
+where
+    I: TryStream<Ok = PipelineClientMessage<Req>> + Sink<PipelineServerMessage<Res>>,
+    S: tower::Service<RequestFrame<Req>, Response = ResponseFrame<Res>>,
+{
+    type Output = Result<(), PipelineConnectionError<I, Res, S::Error>>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        loop {
+            this.poll_recv(cx)?;
+            let mut progress = this.poll_in_flight(cx)?;
+            progress |= this.poll_casts(cx)?;
+            this.poll_send(cx)?;
+            progress |= this.poll_keepalive(cx)?;
+
+            if (this.read_closed || this.draining)
+                && this.next_request.is_none()
+                && this.in_flight.is_empty()
+                && this.casts.is_empty()
+                && this.responses.is_empty()
+            {
+                return this
+                    .transport
+                    .as_mut()
+                    .poll_close(cx)
+                    .map_err(ConnectionError::BrokenTransportSend);
+            }
+            // Requests that just completed may have freed up capacity in the service
+            if !progress {
+                return Poll::Pending;
+            }
+        }
+    }
+}
+
+impl<I, S, Req, Res> Drain for PipelineConnection<I, S, Req, Res>
+where
+    S: tower::Service<RequestFrame<Req>, Response = ResponseFrame<Res>>,
+{
+    fn drain(&mut self) {
+        if !self.draining {
+            self.draining = true;
+            // Doesn't take the place of a response, so it can jump ahead of any queued ones
+            self.responses.push_front(PipelineServerMessage::GoAway);
+        }
+    }
+}
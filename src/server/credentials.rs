@@ -0,0 +1,183 @@
+use std::collections::HashSet;
+use std::future::{self, Future};
+use std::io;
+use std::os::fd::{AsFd, AsRawFd};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use tower::{BoxError, Layer, Service};
+
+use crate::{ConnectionInfo, RemoteError, RemoteErrorKind, Request};
+
+/// Identity of the process on the other end of a Unix socket.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct PeerCredentials {
+    pub uid: u32,
+    pub gid: u32,
+    /// Only available on Linux and Android.
+    pub pid: Option<i32>,
+}
+
+impl PeerCredentials {
+    /// Reads the credentials of the peer connected to the socket.
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub fn from_socket(socket: &impl AsFd) -> io::Result<Self> {
+        let mut cred = libc::ucred {
+            pid: 0,
+            uid: 0,
+            gid: 0,
+        };
+        let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+        // SAFETY: the buffer and length match the layout of ucred, which is what SO_PEERCRED
+        // writes
+        let res = unsafe {
+            libc::getsockopt(
+                socket.as_fd().as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_PEERCRED,
+                &mut cred as *mut libc::ucred as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        if res != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self {
+            uid: cred.uid,
+            gid: cred.gid,
+            pid: Some(cred.pid),
+        })
+    }
+
+    /// Reads the credentials of the peer connected to the socket.
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    pub fn from_socket(socket: &impl AsFd) -> io::Result<Self> {
+        let mut uid = 0;
+        let mut gid = 0;
+        // SAFETY: getpeereid only writes to the two out parameters
+        let res = unsafe { libc::getpeereid(socket.as_fd().as_raw_fd(), &mut uid, &mut gid) };
+        if res != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self {
+            uid,
+            gid,
+            pid: None,
+        })
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+#[error("peer is not allowed to connect: {0}")]
+pub struct PermissionDenied(String);
+
+impl From<PermissionDenied> for RemoteError {
+    fn from(e: PermissionDenied) -> Self {
+        RemoteError::new(RemoteErrorKind::PermissionDenied, e.to_string())
+    }
+}
+
+/// Only allows connections and requests from peers whose UID is in the allowed set. Peers without
+/// credentials are always rejected.
+///
+/// Applied to a handler, requests from disallowed peers fail with
+/// [`RemoteErrorKind::PermissionDenied`] without reaching it. Applied to the server's
+/// `MakeService`, connections from disallowed peers are dropped before a handler is created.
+///
+/// The handler's own errors are converted to a [`RemoteError`] rather than boxed, so they reach
+/// the client with the same kind they would have without the filter.
+#[derive(Clone, Debug)]
+pub struct UidFilterLayer {
+    allowed: Arc<HashSet<u32>>,
+}
+
+impl UidFilterLayer {
+    pub fn new(uids: impl IntoIterator<Item = u32>) -> Self {
+        Self {
+            allowed: Arc::new(uids.into_iter().collect()),
+        }
+    }
+
+    /// Only allows peers running as the same user as this process.
+    pub fn current_user() -> Self {
+        // SAFETY: getuid has no preconditions and can't fail
+        Self::new([unsafe { libc::getuid() }])
+    }
+}
+
+impl<S> Layer<S> for UidFilterLayer {
+    type Service = UidFilter<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        UidFilter {
+            inner,
+            allowed: self.allowed.clone(),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct UidFilter<S> {
+    inner: S,
+    allowed: Arc<HashSet<u32>>,
+}
+
+impl<S> UidFilter<S> {
+    fn check(&self, credentials: Option<&PeerCredentials>) -> Result<(), PermissionDenied> {
+        match credentials {
+            Some(credentials) if self.allowed.contains(&credentials.uid) => Ok(()),
+            Some(credentials) => Err(PermissionDenied(format!(
+                "uid {} is not allowed",
+                credentials.uid
+            ))),
+            None => Err(PermissionDenied("peer credentials unavailable".to_owned())),
+        }
+    }
+}
+
+impl<S, T> Service<Request<T>> for UidFilter<S>
+where
+    S: Service<Request<T>>,
+    S::Error: Into<RemoteError>,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = RemoteError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, req: Request<T>) -> Self::Future {
+        if let Err(e) = self.check(req.extensions.get::<PeerCredentials>()) {
+            return Box::pin(future::ready(Err(e.into())));
+        }
+        let res = self.inner.call(req);
+        Box::pin(async move { res.await.map_err(Into::into) })
+    }
+}
+
+impl<S> Service<ConnectionInfo> for UidFilter<S>
+where
+    S: Service<ConnectionInfo>,
+    S::Error: Into<BoxError>,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = BoxError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, info: ConnectionInfo) -> Self::Future {
+        if let Err(e) = self.check(info.credentials.as_ref()) {
+            return Box::pin(future::ready(Err(e.into())));
+        }
+        let res = self.inner.call(info);
+        Box::pin(async move { res.await.map_err(Into::into) })
+    }
+}
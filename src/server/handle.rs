@@ -0,0 +1,111 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use tokio_util::sync::CancellationToken;
+
+use crate::{ConnectionId, ConnectionInfo};
+
+/// An open connection as seen by [`ServerHandle::connections`].
+#[derive(Clone, Debug)]
+pub struct ActiveConnection {
+    pub info: ConnectionInfo,
+    /// Requests the handler is currently processing for this connection.
+    pub in_flight: usize,
+}
+
+struct Entry {
+    info: ConnectionInfo,
+    in_flight: Arc<AtomicUsize>,
+    close: CancellationToken,
+}
+
+/// Lets the connections of a running server be inspected and closed. Clones refer to the same
+/// server.
+///
+/// Closing a connection works like shutting down the server does for it: without a drain timeout
+/// it's dropped immediately, otherwise it's told to go away and given until the timeout to finish
+/// its in-flight requests.
+#[derive(Clone, Default)]
+pub struct ServerHandle {
+    connections: Arc<Mutex<HashMap<ConnectionId, Entry>>>,
+}
+
+impl ServerHandle {
+    /// Returns the open connections, oldest first.
+    pub fn connections(&self) -> Vec<ActiveConnection> {
+        let mut connections: Vec<_> = self
+            .lock()
+            .values()
+            .map(|entry| ActiveConnection {
+                info: entry.info.clone(),
+                in_flight: entry.in_flight.load(Ordering::Relaxed),
+            })
+            .collect();
+        connections.sort_by_key(|connection| connection.info.id);
+        connections
+    }
+
+    /// Closes the connection with the given ID. Returns false if it isn't open.
+    pub fn close(&self, id: ConnectionId) -> bool {
+        match self.lock().get(&id) {
+            Some(entry) => {
+                entry.close.cancel();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Closes every open connection. The server keeps accepting new connections.
+    pub fn close_all(&self) {
+        for entry in self.lock().values() {
+            entry.close.cancel();
+        }
+    }
+
+    /// Adds a connection to the registry until the returned value is dropped. Closing the server
+    /// closes the connection as well.
+    pub(crate) fn register(
+        &self,
+        info: &ConnectionInfo,
+        shutdown: &CancellationToken,
+    ) -> RegisteredConnection {
+        let in_flight = Arc::new(AtomicUsize::new(0));
+        let close = shutdown.child_token();
+        self.lock().insert(
+            info.id,
+            Entry {
+                info: info.clone(),
+                in_flight: in_flight.clone(),
+                close: close.clone(),
+            },
+        );
+        RegisteredConnection {
+            handle: self.clone(),
+            id: info.id,
+            in_flight,
+            close,
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<ConnectionId, Entry>> {
+        self.connections
+            .lock()
+            .expect("connection registry poisoned")
+    }
+}
+
+pub(crate) struct RegisteredConnection {
+    handle: ServerHandle,
+    id: ConnectionId,
+    pub(crate) in_flight: Arc<AtomicUsize>,
+    /// Cancelled when the connection is closed through the handle or the server shuts down.
+    pub(crate) close: CancellationToken,
+}
+
+impl Drop for RegisteredConnection {
+    fn drop(&mut self) {
+        self.handle.lock().remove(&self.id);
+    }
+}
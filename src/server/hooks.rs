@@ -0,0 +1,55 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::{ConnectionId, ConnectionInfo, RemoteErrorKind};
+
+/// Why a connection ended.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DisconnectReason {
+    /// The client closed the connection, or the server closed it after it sat idle.
+    Closed,
+    /// The server shut down while the connection was open.
+    Shutdown,
+    /// The connection was closed through a [`ServerHandle`](crate::ServerHandle).
+    Evicted,
+    /// Reading from or writing to the transport failed.
+    Transport(String),
+    /// The client stopped answering keepalive pings.
+    Unresponsive,
+    /// The connection's handler returned an error from `poll_ready` or `call`.
+    Service(String),
+}
+
+/// Details about a request the handler finished, passed to the server's request hook.
+#[derive(Clone, Debug)]
+pub struct RequestOutcome {
+    pub connection_id: ConnectionId,
+    /// Time from when the handler was called until it returned a response.
+    pub duration: Duration,
+    /// The kind of error sent to the client, or `None` if the request succeeded.
+    pub error: Option<RemoteErrorKind>,
+}
+
+pub(crate) type OnRequestComplete = Arc<dyn Fn(&RequestOutcome) + Send + Sync>;
+
+/// Callbacks the server runs as connections and requests come and go.
+#[derive(Clone, Default)]
+pub(crate) struct Hooks {
+    pub(crate) on_connect: Option<Arc<dyn Fn(&ConnectionInfo) + Send + Sync>>,
+    pub(crate) on_disconnect: Option<Arc<dyn Fn(&ConnectionInfo, &DisconnectReason) + Send + Sync>>,
+    pub(crate) on_request_complete: Option<OnRequestComplete>,
+}
+
+impl Hooks {
+    pub(crate) fn connected(&self, info: &ConnectionInfo) {
+        if let Some(on_connect) = &self.on_connect {
+            on_connect(info);
+        }
+    }
+
+    pub(crate) fn disconnected(&self, info: &ConnectionInfo, reason: &DisconnectReason) {
+        if let Some(on_disconnect) = &self.on_disconnect {
+            on_disconnect(info, reason);
+        }
+    }
+}
@@ -2,13 +2,16 @@ use std::error::Error;
 use std::fmt::Debug;
 use std::io;
 use std::marker::PhantomData;
-use std::pin::Pin;
+use std::pin::{pin, Pin};
+use std::sync::Arc;
 use std::task::{Context, Poll};
+use std::time::Duration;
 
 use background_service::error::BoxedError;
 use background_service::{BackgroundService, ServiceContext};
 use bytes::{Bytes, BytesMut};
 use eyre::Context as EyreContext;
+use futures::future::{self, Either};
 use futures::{Future, Stream};
 use futures_cancel::FutureExt;
 use http::Method;
@@ -17,27 +20,34 @@ use hyper::body::{Body, Incoming};
 use hyper_util::rt::{TokioExecutor, TokioIo};
 use hyper_util::service::TowerToHyperService;
 use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::Mutex;
 use tokio_serde::{Deserializer, Serializer};
-use tokio_stream::StreamExt;
 use tower::{BoxError, MakeService, Service, ServiceBuilder};
-use tracing::error;
+use tracing::{error, warn};
 
-use crate::{Keyed, Request, RoutedRequest};
+use crate::{
+    AcceptPolicy, AcceptStats, ConnectionInfo, Extensions, InspectConnection, Keyed,
+    MakeServicePolicy, MakeServiceStats, Request, RoutedRequest, Trailers, TransportInfo,
+};
 
 pub struct Server<K, H, S, I, E, Res>
 where
-    K: MakeService<(), hyper::Request<Incoming>, Service = H>,
+    K: MakeService<ConnectionInfo, hyper::Request<Incoming>, Service = H>,
     H: Service<hyper::Request<Incoming>, Response = http::Response<Res>> + Send + 'static,
 {
     incoming: S,
     handler: K,
+    inspect: Option<InspectConnection<I>>,
+    drain_timeout: Option<Duration>,
+    accept_policy: AcceptPolicy<E>,
+    make_policy: MakeServicePolicy<K::MakeError>,
 
     _phantom: PhantomData<(H, I, E)>,
 }
 
 impl<K, H, S, I, E, Res> Server<K, H, S, I, E, Res>
 where
-    K: MakeService<(), hyper::Request<Incoming>, Service = H> + Send,
+    K: MakeService<ConnectionInfo, hyper::Request<Incoming>, Service = H> + Send + 'static,
     K::MakeError: Error + Send + Sync + 'static,
     K::Future: Send,
     K::MakeError: Debug,
@@ -46,7 +56,7 @@ where
     H::Error: Into<Box<dyn Error + Send + Sync>>,
     S: Stream<Item = Result<I, E>> + Send,
     I: AsyncRead + AsyncWrite + Send + Unpin + 'static,
-    E: Send,
+    E: Debug + Send,
     Res: Body + Send + 'static,
     <Res as Body>::Error: Error + Send + Sync,
     <Res as Body>::Data: Send,
@@ -55,35 +65,133 @@ where
         Self {
             incoming,
             handler,
+            inspect: None,
+            drain_timeout: None,
+            accept_policy: AcceptPolicy::default(),
+            make_policy: MakeServicePolicy::default(),
             _phantom: Default::default(),
         }
     }
 
+    /// Sets a function that fills in the transport-specific details of each accepted connection,
+    /// such as the peer address.
+    pub fn with_connection_info<F>(mut self, inspect: F) -> Self
+    where
+        F: Fn(&I, ConnectionInfo) -> ConnectionInfo + Send + Sync + 'static,
+    {
+        self.inspect = Some(Box::new(inspect));
+        self
+    }
+
+    /// Fills in the details the transport knows about each accepted connection, such as the peer
+    /// address of a TCP connection. This replaces any function set with
+    /// [`Server::with_connection_info`].
+    pub fn with_transport_info(self) -> Self
+    where
+        I: TransportInfo,
+    {
+        self.with_connection_info(|conn, info| conn.connection_info(info))
+    }
+
+    /// Shuts down gracefully instead of dropping every connection at once. When the server's
+    /// cancellation token fires, it stops accepting connections and asks clients to close theirs.
+    /// Requests that are already running are given up to `timeout` to finish before they're
+    /// cancelled.
+    pub fn with_drain_timeout(mut self, timeout: Duration) -> Self {
+        self.drain_timeout = Some(timeout);
+        self
+    }
+
+    /// Sets how the server handles errors from the incoming connection stream.
+    pub fn with_accept_policy(mut self, accept_policy: AcceptPolicy<E>) -> Self {
+        self.accept_policy = accept_policy;
+        self
+    }
+
+    /// Returns a handle to the number of accept errors the server has handled. Setting a new
+    /// accept policy afterwards starts new counts, so call this after
+    /// [`Server::with_accept_policy`].
+    pub fn accept_stats(&self) -> AcceptStats {
+        self.accept_policy.stats()
+    }
+
+    /// Sets how the server handles its `MakeService` failing to create a handler for a
+    /// connection.
+    pub fn with_make_service_policy(
+        mut self,
+        make_policy: MakeServicePolicy<K::MakeError>,
+    ) -> Self {
+        self.make_policy = make_policy;
+        self
+    }
+
+    /// Returns a handle to the number of times the server's `MakeService` failed. Setting a new
+    /// policy afterwards starts new counts, so call this after
+    /// [`Server::with_make_service_policy`].
+    pub fn make_service_stats(&self) -> MakeServiceStats {
+        self.make_policy.stats()
+    }
+
     async fn run_server(mut self, mut context: ServiceContext) -> Result<(), BoxedError> {
         let incoming = self.incoming;
+        let make_service = Arc::new(Mutex::new(self.handler));
         futures::pin_mut!(incoming);
-        while let Ok(Some(Ok(stream))) = incoming
-            .next()
-            .cancel_on_shutdown(&context.cancellation_token())
+        while let Some(stream) = self
+            .accept_policy
+            .accept(&mut incoming, &context)
             .await
+            .map_err(|e| format!("Error accepting connection: {e:?}"))?
         {
-            let handler = self
-                .handler
-                .make_service(())
-                .await
-                .wrap_err("Error making service")?;
-
+            let info = ConnectionInfo::accepted(&stream, self.inspect.as_ref());
+            let make_service = make_service.clone();
+            let make_policy = self.make_policy.clone();
+            let drain_timeout = self.drain_timeout;
             context.add_service(("http_handler", move |context: ServiceContext| async move {
+                let Some(handler) = make_policy
+                    .make_service::<_, hyper::Request<Incoming>>(&make_service, &info, &context)
+                    .await
+                else {
+                    return Ok(());
+                };
                 let service = ServiceBuilder::default()
                     .layer_fn(|inner| TowerToHyperService::new(inner))
                     .service(handler);
 
-                if let Ok(Err(e)) =
-                    hyper_util::server::conn::auto::Builder::new(TokioExecutor::new())
-                        .serve_connection(TokioIo::new(stream), service)
-                        .cancel_on_shutdown(&context.cancellation_token())
+                let builder = hyper_util::server::conn::auto::Builder::new(TokioExecutor::new());
+                let connection = builder.serve_connection(TokioIo::new(stream), service);
+                let cancellation_token = context.cancellation_token();
+                let res = match drain_timeout {
+                    Some(drain_timeout) => {
+                        let mut connection = pin!(connection);
+                        match future::select(
+                            connection.as_mut(),
+                            pin!(cancellation_token.cancelled()),
+                        )
+                        .await
+                        {
+                            Either::Left((res, _)) => Some(res),
+                            Either::Right(_) => {
+                                // Sends GOAWAY to HTTP/2 clients and closes idle HTTP/1
+                                // connections
+                                connection.as_mut().graceful_shutdown();
+                                tokio::time::timeout(drain_timeout, connection)
+                                    .await
+                                    .inspect_err(|_| {
+                                        warn!(
+                                            "Connection did not drain within {drain_timeout:?}, \
+                                             cancelling it"
+                                        )
+                                    })
+                                    .ok()
+                            }
+                        }
+                    }
+                    None => connection
+                        .cancel_on_shutdown(&cancellation_token)
                         .await
-                {
+                        .ok(),
+                };
+                if let Some(Err(e)) = res {
                     error!("Error serving connection: {e:?}");
                 }
 
@@ -97,7 +205,7 @@ where
 
 impl<K, H, S, I, E, Res> BackgroundService for Server<K, H, S, I, E, Res>
 where
-    K: MakeService<(), hyper::Request<Incoming>, Service = H> + Send,
+    K: MakeService<ConnectionInfo, hyper::Request<Incoming>, Service = H> + Send + 'static,
     K::MakeError: Error + Send + Sync + 'static,
     K::Future: Send,
     K::MakeError: Debug,
@@ -106,7 +214,7 @@ where
     H::Error: Into<Box<dyn Error + Send + Sync>>,
     S: Stream<Item = Result<I, E>> + Send,
     I: AsyncRead + AsyncWrite + Send + Unpin + 'static,
-    E: Send,
+    E: Debug + Send,
     Res: Body + Send + 'static,
     <Res as Body>::Error: Error + Send + Sync,
     <Res as Body>::Data: Send,
@@ -197,9 +305,20 @@ where
         let mut serializer = self.serializer.clone();
         let context = self.context.clone();
         Box::pin(async move {
+            // Headers that aren't valid strings can't be represented as metadata
+            let metadata = req
+                .headers()
+                .iter()
+                .filter_map(|(name, value)| Some((name.as_str(), value.to_str().ok()?)))
+                .collect();
+            let trailers = Trailers::default();
             let res = inner
                 .call(Request {
                     context,
+                    deadline: None,
+                    metadata,
+                    trailers: trailers.clone(),
+                    extensions: Extensions::default(),
                     value: RoutedRequest {
                         route: req.uri().to_string(),
                         key: req.method().to_owned().into(),
@@ -217,7 +336,11 @@ where
                     },
                 })
                 .await?;
-            Ok(hyper::Response::builder()
+            let mut builder = hyper::Response::builder();
+            for (name, value) in trailers.take() {
+                builder = builder.header(name, value);
+            }
+            Ok(builder
                 .body(Full::new(
                     Pin::new(&mut serializer)
                         .serialize(&res)
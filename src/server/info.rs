@@ -0,0 +1,87 @@
+use std::fmt::{Display, Formatter};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::SystemTime;
+
+static NEXT_CONNECTION_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Identifies an accepted connection. IDs are unique for the lifetime of the process.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ConnectionId(u64);
+
+impl ConnectionId {
+    fn next() -> Self {
+        Self(NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn as_u64(&self) -> u64 {
+        self.0
+    }
+}
+
+impl Display for ConnectionId {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Details about an accepted connection. This is passed to the server's `MakeService` and is
+/// also available from each request's extensions.
+#[derive(Clone, Debug)]
+pub struct ConnectionInfo {
+    pub id: ConnectionId,
+    pub accepted_at: SystemTime,
+    /// Remote address for network transports such as TCP.
+    pub peer_addr: Option<SocketAddr>,
+    /// Name of the endpoint the connection was accepted on for IPC transports.
+    pub endpoint: Option<String>,
+    /// Name of the listener that accepted the connection when the server uses [`Listeners`].
+    ///
+    /// [`Listeners`]: crate::Listeners
+    pub listener: Option<String>,
+    /// Identity of the peer process for Unix socket transports.
+    #[cfg(unix)]
+    pub credentials: Option<crate::PeerCredentials>,
+}
+
+impl ConnectionInfo {
+    pub(crate) fn accepted<I>(conn: &I, inspect: Option<&InspectConnection<I>>) -> Self {
+        let info = Self {
+            id: ConnectionId::next(),
+            accepted_at: SystemTime::now(),
+            peer_addr: None,
+            endpoint: None,
+            listener: None,
+            #[cfg(unix)]
+            credentials: None,
+        };
+        match inspect {
+            Some(inspect) => inspect(conn, info),
+            None => info,
+        }
+    }
+
+    pub fn with_peer_addr(mut self, peer_addr: SocketAddr) -> Self {
+        self.peer_addr = Some(peer_addr);
+        self
+    }
+
+    pub fn with_endpoint(mut self, endpoint: impl Into<String>) -> Self {
+        self.endpoint = Some(endpoint.into());
+        self
+    }
+
+    pub fn with_listener(mut self, listener: impl Into<String>) -> Self {
+        self.listener = Some(listener.into());
+        self
+    }
+
+    #[cfg(unix)]
+    pub fn with_credentials(mut self, credentials: crate::PeerCredentials) -> Self {
+        self.credentials = Some(credentials);
+        self
+    }
+}
+
+/// Fills in the transport-specific parts of [`ConnectionInfo`] for a newly accepted connection.
+pub type InspectConnection<I> = Box<dyn Fn(&I, ConnectionInfo) -> ConnectionInfo + Send + Sync>;
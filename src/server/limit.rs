@@ -0,0 +1,155 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use background_service::ServiceContext;
+use futures_cancel::FutureExt;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// What the server does with new connections once it has reached its connection limit.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OnConnectionLimit {
+    /// Stops accepting until a connection closes. New clients wait in the listener's backlog.
+    Pause,
+    /// Accepts the connection, tells the client the server is busy, and closes it.
+    Reject,
+}
+
+#[derive(Debug, Default)]
+struct Counts {
+    current: AtomicUsize,
+    peak: AtomicUsize,
+}
+
+/// Number of connections the server currently has open, along with the most it has had open at
+/// once. Clones share the same counts, so this can be kept after the server starts running.
+#[derive(Clone, Debug, Default)]
+pub struct ConnectionStats {
+    counts: Arc<Counts>,
+}
+
+impl ConnectionStats {
+    pub fn current(&self) -> usize {
+        self.counts.current.load(Ordering::Relaxed)
+    }
+
+    pub fn peak(&self) -> usize {
+        self.counts.peak.load(Ordering::Relaxed)
+    }
+}
+
+/// Keeps a connection counted until it's dropped.
+pub(crate) struct OpenConnection {
+    stats: ConnectionStats,
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+impl Drop for OpenConnection {
+    fn drop(&mut self) {
+        self.stats.counts.current.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct ConnectionLimiter {
+    limit: Option<(Arc<Semaphore>, OnConnectionLimit)>,
+    // Slot that was waited for before accepting in pause mode
+    reserved: Option<OwnedSemaphorePermit>,
+    stats: ConnectionStats,
+}
+
+impl ConnectionLimiter {
+    pub(crate) fn set_limit(&mut self, max_connections: usize, on_limit: OnConnectionLimit) {
+        self.limit = Some((Arc::new(Semaphore::new(max_connections)), on_limit));
+        self.reserved = None;
+    }
+
+    pub(crate) fn stats(&self) -> ConnectionStats {
+        self.stats.clone()
+    }
+
+    /// In pause mode, waits until there's room for another connection. Returns false if the
+    /// server shut down while waiting.
+    pub(crate) async fn reserve(&mut self, context: &ServiceContext) -> bool {
+        let Some((semaphore, OnConnectionLimit::Pause)) = &self.limit else {
+            return true;
+        };
+        if self.reserved.is_some() {
+            return true;
+        }
+        match semaphore
+            .clone()
+            .acquire_owned()
+            .cancel_on_shutdown(&context.cancellation_token())
+            .await
+        {
+            Ok(Ok(permit)) => {
+                self.reserved = Some(permit);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Counts a newly accepted connection. Returns `None` if the server is full and the
+    /// connection should be rejected.
+    pub(crate) fn open(&mut self) -> Option<OpenConnection> {
+        let permit = match &self.limit {
+            Some((semaphore, _)) => match self.reserved.take() {
+                Some(permit) => Some(permit),
+                None => Some(semaphore.clone().try_acquire_owned().ok()?),
+            },
+            None => None,
+        };
+        let counts = &self.stats.counts;
+        let current = counts.current.fetch_add(1, Ordering::Relaxed) + 1;
+        counts.peak.fetch_max(current, Ordering::Relaxed);
+        Some(OpenConnection {
+            stats: self.stats.clone(),
+            _permit: permit,
+        })
+    }
+}
+
+/// What a multiplex server does with new requests once an in-flight limit is reached.
+#[cfg(feature = "multiplex")]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OnRequestLimit {
+    /// Queues new requests until a running one finishes. Frames for the requests that are already
+    /// running, like cancellations and stream items, keep being read while there's room in the
+    /// queue. Once it's full, the connection holds on to the next request and stops reading until
+    /// a queued request starts, which pushes back on the client through the transport. Anything
+    /// the client sends after that request waits with it, including items for running uploads, so
+    /// the queue should be deep enough for the calls a client makes at once.
+    #[default]
+    Backpressure,
+    /// Responds to the request with an overloaded error.
+    Reject,
+}
+
+#[cfg(feature = "multiplex")]
+#[derive(Clone, Debug)]
+pub(crate) struct RequestLimits {
+    pub(crate) per_connection: Option<usize>,
+    // Tokio's semaphore hands out permits in the order they were requested, so connections
+    // waiting on it take turns
+    pub(crate) global: Option<Arc<Semaphore>>,
+    pub(crate) on_limit: OnRequestLimit,
+    // Requests each connection can have waiting for room
+    pub(crate) queue_depth: usize,
+    // Frames each connection can have waiting to be sent before it stops pulling stream items and
+    // notifications
+    pub(crate) send_buffer: usize,
+}
+
+#[cfg(feature = "multiplex")]
+impl Default for RequestLimits {
+    fn default() -> Self {
+        Self {
+            per_connection: None,
+            global: None,
+            on_limit: OnRequestLimit::default(),
+            queue_depth: 64,
+            send_buffer: 16,
+        }
+    }
+}
@@ -0,0 +1,234 @@
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use futures::stream::{BoxStream, SelectAll};
+use futures::{Sink, Stream, StreamExt, TryStream, TryStreamExt};
+use pin_project_lite::pin_project;
+use tower::BoxError;
+
+use crate::{ConnectionInfo, InspectConnection, TransportInfo};
+
+/// Merges several incoming connection streams so one [`Server`](crate::Server) can serve all of
+/// them. The listeners can use different transports as long as they speak the same messages,
+/// which usually means wrapping each one with the same codec.
+///
+/// Servers created with [`Server::pipeline_listeners`](crate::Server::pipeline_listeners) or
+/// [`Server::multiplex_listeners`](crate::Server::multiplex_listeners) record which listener each
+/// connection came from. Servers created otherwise can do the same with
+/// [`Server::with_transport_info`](crate::Server::with_transport_info).
+pub struct Listeners<In, Out> {
+    incoming: SelectAll<BoxStream<'static, Result<ListenerConnection<In, Out>, BoxError>>>,
+}
+
+impl<In, Out> Default for Listeners<In, Out> {
+    fn default() -> Self {
+        Self {
+            incoming: SelectAll::new(),
+        }
+    }
+}
+
+impl<In, Out> Listeners<In, Out>
+where
+    In: 'static,
+    Out: 'static,
+{
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a listener. Its connections are tagged with `name`.
+    pub fn with_listener<S, I, E>(self, name: impl Into<String>, incoming: S) -> Self
+    where
+        S: Stream<Item = Result<I, E>> + Send + 'static,
+        I: TryStream<Ok = In> + Sink<Out> + Send + 'static,
+        <I as TryStream>::Error: Into<BoxError>,
+        <I as Sink<Out>>::Error: Into<BoxError>,
+        E: Into<BoxError>,
+    {
+        self.add(name.into(), incoming, None)
+    }
+
+    /// Adds a listener along with a function that fills in the transport-specific details of its
+    /// connections, like [`Server::with_connection_info`](crate::Server::with_connection_info)
+    /// does for a single listener.
+    pub fn with_listener_info<S, I, E, F>(
+        self,
+        name: impl Into<String>,
+        incoming: S,
+        inspect: F,
+    ) -> Self
+    where
+        S: Stream<Item = Result<I, E>> + Send + 'static,
+        I: TryStream<Ok = In> + Sink<Out> + Send + 'static,
+        <I as TryStream>::Error: Into<BoxError>,
+        <I as Sink<Out>>::Error: Into<BoxError>,
+        E: Into<BoxError>,
+        F: Fn(&I, ConnectionInfo) -> ConnectionInfo + Send + Sync + 'static,
+    {
+        self.add(name.into(), incoming, Some(Arc::new(Box::new(inspect))))
+    }
+
+    fn add<S, I, E>(
+        mut self,
+        name: String,
+        incoming: S,
+        inspect: Option<Arc<InspectConnection<I>>>,
+    ) -> Self
+    where
+        S: Stream<Item = Result<I, E>> + Send + 'static,
+        I: TryStream<Ok = In> + Sink<Out> + Send + 'static,
+        <I as TryStream>::Error: Into<BoxError>,
+        <I as Sink<Out>>::Error: Into<BoxError>,
+        E: Into<BoxError>,
+    {
+        let name: Arc<str> = name.into();
+        self.incoming.push(
+            incoming
+                .map_ok(move |transport| ListenerConnection {
+                    listener: name.clone(),
+                    transport: Box::pin(Accepted {
+                        transport,
+                        inspect: inspect.clone(),
+                    }),
+                })
+                .map_err(Into::into)
+                .boxed(),
+        );
+        self
+    }
+}
+
+impl<In, Out> Stream for Listeners<In, Out> {
+    type Item = Result<ListenerConnection<In, Out>, BoxError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.incoming.poll_next_unpin(cx)
+    }
+}
+
+/// A connection accepted by one of the [`Listeners`].
+pub struct ListenerConnection<In, Out> {
+    listener: Arc<str>,
+    transport: Pin<Box<dyn Transport<In, Out>>>,
+}
+
+impl<In, Out> ListenerConnection<In, Out> {
+    /// Name of the listener that accepted the connection.
+    pub fn listener(&self) -> &str {
+        &self.listener
+    }
+
+    /// Adds the listener's name to `info`, along with anything the listener's inspect function
+    /// fills in.
+    pub fn connection_info(&self, info: ConnectionInfo) -> ConnectionInfo {
+        self.transport
+            .connection_info(info.with_listener(self.listener.as_ref()))
+    }
+}
+
+impl<In, Out> TransportInfo for ListenerConnection<In, Out> {
+    fn connection_info(&self, info: ConnectionInfo) -> ConnectionInfo {
+        ListenerConnection::connection_info(self, info)
+    }
+}
+
+impl<In, Out> Stream for ListenerConnection<In, Out> {
+    type Item = Result<In, BoxError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.transport.as_mut().poll_next(cx)
+    }
+}
+
+impl<In, Out> Sink<Out> for ListenerConnection<In, Out> {
+    type Error = BoxError;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), BoxError>> {
+        self.transport.as_mut().poll_ready(cx)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: Out) -> Result<(), BoxError> {
+        self.transport.as_mut().start_send(item)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), BoxError>> {
+        self.transport.as_mut().poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), BoxError>> {
+        self.transport.as_mut().poll_close(cx)
+    }
+}
+
+// Lets transports of different types be stored together
+trait Transport<In, Out>:
+    Stream<Item = Result<In, BoxError>> + Sink<Out, Error = BoxError> + Send
+{
+    fn connection_info(&self, info: ConnectionInfo) -> ConnectionInfo;
+}
+
+pin_project! {
+    struct Accepted<I> {
+        #[pin]
+        transport: I,
+        inspect: Option<Arc<InspectConnection<I>>>,
+    }
+}
+
+impl<I, In, Out> Transport<In, Out> for Accepted<I>
+where
+    I: TryStream<Ok = In> + Sink<Out> + Send,
+    <I as TryStream>::Error: Into<BoxError>,
+    <I as Sink<Out>>::Error: Into<BoxError>,
+{
+    fn connection_info(&self, info: ConnectionInfo) -> ConnectionInfo {
+        match &self.inspect {
+            Some(inspect) => inspect(&self.transport, info),
+            None => info,
+        }
+    }
+}
+
+impl<I> Stream for Accepted<I>
+where
+    I: TryStream,
+    I::Error: Into<BoxError>,
+{
+    type Item = Result<I::Ok, BoxError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.project()
+            .transport
+            .try_poll_next(cx)
+            .map(|item| item.map(|res| res.map_err(Into::into)))
+    }
+}
+
+impl<I, Out> Sink<Out> for Accepted<I>
+where
+    I: Sink<Out>,
+    I::Error: Into<BoxError>,
+{
+    type Error = BoxError;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), BoxError>> {
+        self.project().transport.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: Out) -> Result<(), BoxError> {
+        self.project()
+            .transport
+            .start_send(item)
+            .map_err(Into::into)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), BoxError>> {
+        self.project().transport.poll_flush(cx).map_err(Into::into)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), BoxError>> {
+        self.project().transport.poll_close(cx).map_err(Into::into)
+    }
+}
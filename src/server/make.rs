@@ -0,0 +1,286 @@
+use std::fmt::{self, Debug};
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use background_service::ServiceContext;
+use futures::future;
+use futures_cancel::FutureExt;
+use tower::MakeService;
+use tracing::{debug, warn};
+
+use crate::{Backoff, ConnectionInfo};
+
+#[derive(Debug, Default)]
+struct Counts {
+    total_failures: AtomicU64,
+    consecutive_failures: AtomicU32,
+    rejected: AtomicU64,
+}
+
+/// Number of times the server's `MakeService` failed to create a handler. Clones share the same
+/// counts, so this can be kept after the server starts running.
+#[derive(Clone, Debug, Default)]
+pub struct MakeServiceStats {
+    counts: Arc<Counts>,
+}
+
+impl MakeServiceStats {
+    pub fn total_failures(&self) -> u64 {
+        self.counts.total_failures.load(Ordering::Relaxed)
+    }
+
+    /// Connections in a row that failed to get a handler. This is what the circuit breaker
+    /// counts.
+    pub fn consecutive_failures(&self) -> u32 {
+        self.counts.consecutive_failures.load(Ordering::Relaxed)
+    }
+
+    /// Connections the `MakeService` refused on purpose, as decided by
+    /// [`MakeServicePolicy::with_rejections`]. These aren't counted as failures.
+    pub fn rejected(&self) -> u64 {
+        self.counts.rejected.load(Ordering::Relaxed)
+    }
+}
+
+type IsRejection<E> = Arc<dyn Fn(&E) -> bool + Send + Sync>;
+
+#[derive(Clone, Debug)]
+struct CircuitBreaker {
+    failure_threshold: u32,
+    cooldown: Duration,
+}
+
+#[derive(Debug, Default)]
+struct BreakerState {
+    open_until: Option<Instant>,
+    // Set while one connection checks whether the service has recovered after the cooldown.
+    // Others are dropped until it finishes so they don't all hit the service at once.
+    probing: bool,
+}
+
+type SharedBreakerState = Arc<Mutex<BreakerState>>;
+
+fn lock_breaker(state: &Mutex<BreakerState>) -> MutexGuard<'_, BreakerState> {
+    state.lock().expect("circuit breaker state poisoned")
+}
+
+// Lets another connection probe if this one finishes without closing or reopening the circuit,
+// such as when it's rejected or the server shuts down during a retry
+struct Probe<'a>(&'a Mutex<BreakerState>);
+
+impl Drop for Probe<'_> {
+    fn drop(&mut self) {
+        lock_breaker(self.0).probing = false;
+    }
+}
+
+/// Decides what the server does when its `MakeService` fails to create a handler for a
+/// connection.
+///
+/// A failure only affects the connection that triggered it. By default, that connection is
+/// dropped right away and the server keeps accepting new ones. Services are created in each
+/// connection's own task, so retrying one connection doesn't hold up the others.
+pub struct MakeServicePolicy<E> {
+    retry: Option<(u32, Backoff)>,
+    circuit_breaker: Option<CircuitBreaker>,
+    is_rejection: Option<IsRejection<E>>,
+    stats: MakeServiceStats,
+    breaker_state: SharedBreakerState,
+}
+
+impl<E> Default for MakeServicePolicy<E> {
+    fn default() -> Self {
+        Self {
+            retry: None,
+            circuit_breaker: None,
+            is_rejection: None,
+            stats: MakeServiceStats::default(),
+            breaker_state: Arc::default(),
+        }
+    }
+}
+
+impl<E> Clone for MakeServicePolicy<E> {
+    fn clone(&self) -> Self {
+        Self {
+            retry: self.retry.clone(),
+            circuit_breaker: self.circuit_breaker.clone(),
+            is_rejection: self.is_rejection.clone(),
+            stats: self.stats.clone(),
+            breaker_state: self.breaker_state.clone(),
+        }
+    }
+}
+
+impl<E> Debug for MakeServicePolicy<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MakeServicePolicy")
+            .field("retry", &self.retry)
+            .field("circuit_breaker", &self.circuit_breaker)
+            .field("is_rejection", &self.is_rejection.is_some())
+            .field("stats", &self.stats)
+            .finish()
+    }
+}
+
+impl<E> MakeServicePolicy<E> {
+    /// Retries creating the service up to `max_retries` times before dropping the connection.
+    pub fn with_retries(mut self, max_retries: u32, backoff: Backoff) -> Self {
+        self.retry = Some((max_retries, backoff));
+        self
+    }
+
+    /// After `failure_threshold` connections in a row fail to get a service, drops new
+    /// connections without calling the `MakeService` until `cooldown` has passed. After that,
+    /// one connection is let through to check whether the service has recovered, and the rest
+    /// are dropped until it succeeds or fails.
+    pub fn with_circuit_breaker(mut self, failure_threshold: u32, cooldown: Duration) -> Self {
+        self.circuit_breaker = Some(CircuitBreaker {
+            failure_threshold: failure_threshold.max(1),
+            cooldown,
+        });
+        self
+    }
+
+    /// Treats errors that `is_rejection` returns true for as the `MakeService` refusing the
+    /// connection on purpose. Those connections are dropped without being retried or counted
+    /// towards the circuit breaker.
+    ///
+    /// For example, peers turned away by [`UidFilter`](crate::UidFilter) can be told apart with
+    /// `|e: &BoxError| e.is::<PermissionDenied>()`.
+    pub fn with_rejections<F>(mut self, is_rejection: F) -> Self
+    where
+        F: Fn(&E) -> bool + Send + Sync + 'static,
+    {
+        self.is_rejection = Some(Arc::new(is_rejection));
+        self
+    }
+
+    /// Returns a handle to the number of failures handled by this policy.
+    pub fn stats(&self) -> MakeServiceStats {
+        self.stats.clone()
+    }
+}
+
+impl<E: Debug> MakeServicePolicy<E> {
+    /// Creates the service for a connection. Returns `None` if the connection should be dropped.
+    pub(crate) async fn make_service<K, R>(
+        &self,
+        make_service: &tokio::sync::Mutex<K>,
+        info: &ConnectionInfo,
+        context: &ServiceContext,
+    ) -> Option<K::Service>
+    where
+        K: MakeService<ConnectionInfo, R, MakeError = E>,
+    {
+        let _probe = {
+            let mut state = lock_breaker(&self.breaker_state);
+            match state.open_until {
+                Some(until) if Instant::now() < until => {
+                    debug!("Dropping connection {}: circuit breaker is open", info.id);
+                    return None;
+                }
+                Some(_) if state.probing => {
+                    debug!(
+                        "Dropping connection {}: waiting to see if the service has recovered",
+                        info.id
+                    );
+                    return None;
+                }
+                Some(_) => {
+                    state.probing = true;
+                    Some(Probe(&self.breaker_state))
+                }
+                None => None,
+            }
+        };
+
+        let mut attempt = 0;
+        let e = loop {
+            match make(make_service, info).await {
+                Ok(service) => {
+                    self.stats
+                        .counts
+                        .consecutive_failures
+                        .store(0, Ordering::Relaxed);
+                    lock_breaker(&self.breaker_state).open_until = None;
+                    return Some(service);
+                }
+                Err(e)
+                    if self
+                        .is_rejection
+                        .as_ref()
+                        .is_some_and(|is_rejection| is_rejection(&e)) =>
+                {
+                    self.stats.counts.rejected.fetch_add(1, Ordering::Relaxed);
+                    debug!("Connection {} was rejected: {e:?}", info.id);
+                    return None;
+                }
+                Err(e) => {
+                    self.stats
+                        .counts
+                        .total_failures
+                        .fetch_add(1, Ordering::Relaxed);
+                    attempt += 1;
+                    match &self.retry {
+                        Some((max_retries, backoff)) if attempt <= *max_retries => {
+                            let delay = backoff.delay(attempt);
+                            debug!(
+                                "Failed to create service for connection {}, retrying in \
+                                 {delay:?}: {e:?}",
+                                info.id
+                            );
+                            tokio::time::sleep(delay)
+                                .cancel_on_shutdown(&context.cancellation_token())
+                                .await
+                                .ok()?;
+                        }
+                        _ => break e,
+                    }
+                }
+            }
+        };
+
+        warn!(
+            "Dropping connection {}: failed to create service ({} failures so far): {e:?}",
+            info.id,
+            self.stats.total_failures()
+        );
+        let consecutive_failures = self
+            .stats
+            .counts
+            .consecutive_failures
+            .fetch_add(1, Ordering::Relaxed)
+            + 1;
+        if let Some(circuit_breaker) = &self.circuit_breaker {
+            if consecutive_failures >= circuit_breaker.failure_threshold {
+                warn!(
+                    "Service creation failed for {consecutive_failures} connections in a row, \
+                     dropping new connections for {:?}",
+                    circuit_breaker.cooldown
+                );
+                lock_breaker(&self.breaker_state).open_until =
+                    Some(Instant::now() + circuit_breaker.cooldown);
+            }
+        }
+        None
+    }
+}
+
+// Only holds the lock while the `MakeService` is polled so that other connections can create
+// their services while this one's future runs
+async fn make<K, R>(
+    make_service: &tokio::sync::Mutex<K>,
+    info: &ConnectionInfo,
+) -> Result<K::Service, K::MakeError>
+where
+    K: MakeService<ConnectionInfo, R>,
+{
+    let future = {
+        let mut make_service = make_service.lock().await;
+        future::poll_fn(|cx| make_service.poll_ready(cx)).await?;
+        make_service.make_service(info.clone())
+    };
+    future.await
+}
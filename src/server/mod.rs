@@ -1,48 +1,243 @@
 use std::fmt::Debug;
 use std::marker::PhantomData;
+use std::sync::Arc;
+use std::time::Duration;
 
 use background_service::error::BoxedError;
 use background_service::{BackgroundService, ServiceContext};
 use futures::{Sink, Stream, TryStream};
 use futures_cancel::FutureExt;
-use tokio_stream::StreamExt;
-use tokio_tower::pipeline;
-use tower::{MakeService, ServiceBuilder};
-use tracing::info;
+use tokio::sync::Mutex;
+use tower::{BoxError, MakeService, ServiceBuilder};
+use tracing::warn;
 
-use crate::service::RequestService;
-use crate::{Pipeline, Request, ServerMode};
+use self::connection::{finish, reject, serve, PipelineConnection};
+use crate::service::{RequestService, ResponseService};
+use crate::{
+    Keepalive, Pipeline, PipelineClientMessage, PipelineServerMessage, RemoteError, Request,
+    ServerMode,
+};
 
+mod accept;
+pub use accept::*;
+#[cfg(feature = "multiplex")]
+mod call;
+#[cfg(feature = "multiplex")]
+pub use call::*;
+mod catch_panic;
+pub use catch_panic::*;
+mod connection;
+#[cfg(unix)]
+mod credentials;
+#[cfg(unix)]
+pub use credentials::*;
+mod handle;
+pub use handle::*;
+mod hooks;
+pub use hooks::*;
+mod info;
+pub use info::*;
+mod limit;
+pub use limit::*;
+mod listeners;
+pub use listeners::*;
+mod make;
+pub use make::*;
 #[cfg(feature = "multiplex")]
 mod multiplex;
+#[cfg(feature = "multiplex")]
+mod reply;
+#[cfg(feature = "multiplex")]
+pub use reply::*;
+mod transport_info;
+pub use transport_info::*;
 
 #[cfg(feature = "http")]
 pub mod http;
 
 pub struct Server<K, H, S, I, E, M, Req, Res>
 where
-    K: MakeService<(), Request<Req>, Service = H>,
-    H: tower::Service<Request<Req>, Response = Res>,
+    K: MakeService<ConnectionInfo, Request<Req>, Service = H>,
+    H: tower::Service<Request<Req>>,
     S: Stream<Item = Result<I, E>>,
     M: ServerMode,
 {
     pub(super) incoming: S,
     pub(super) handler: K,
+    pub(super) inspect: Option<InspectConnection<I>>,
+    pub(super) drain_timeout: Option<Duration>,
+    pub(super) accept_policy: AcceptPolicy<E>,
+    pub(super) make_policy: MakeServicePolicy<K::MakeError>,
+    pub(super) keepalive: Keepalive,
+    pub(super) hooks: Hooks,
+    pub(super) handle: ServerHandle,
+    pub(super) limiter: ConnectionLimiter,
+    #[cfg(feature = "multiplex")]
+    pub(super) request_limits: RequestLimits,
     pub(super) _phantom: PhantomData<(M, H, Req, Res)>,
 }
 
+impl<K, H, S, I, E, M, Req, Res> Server<K, H, S, I, E, M, Req, Res>
+where
+    K: MakeService<ConnectionInfo, Request<Req>, Service = H>,
+    H: tower::Service<Request<Req>>,
+    S: Stream<Item = Result<I, E>>,
+    M: ServerMode,
+{
+    /// Adds a function that fills in the transport-specific details of each accepted connection,
+    /// such as the peer address. If this is called more than once, the functions run in order.
+    pub fn with_connection_info<F>(mut self, inspect: F) -> Self
+    where
+        F: Fn(&I, ConnectionInfo) -> ConnectionInfo + Send + Sync + 'static,
+    {
+        self.inspect = Some(match self.inspect.take() {
+            Some(prev) => Box::new(move |conn: &I, info| inspect(conn, prev(conn, info))),
+            None => Box::new(inspect),
+        });
+        self
+    }
+
+    /// Fills in the details the transport knows about each accepted connection, such as the peer
+    /// address of a TCP connection or the endpoint and peer credentials of an IPC connection.
+    pub fn with_transport_info(self) -> Self
+    where
+        I: TransportInfo,
+    {
+        self.with_connection_info(|conn, info| conn.connection_info(info))
+    }
+
+    /// Shuts down gracefully instead of dropping every connection at once. When the server's
+    /// cancellation token fires, it stops accepting connections and each connection stops
+    /// accepting requests. Requests that are already running are given up to `timeout` to finish
+    /// before they're cancelled.
+    pub fn with_drain_timeout(mut self, timeout: Duration) -> Self {
+        self.drain_timeout = Some(timeout);
+        self
+    }
+
+    /// Sets how the server handles errors from the incoming connection stream.
+    pub fn with_accept_policy(mut self, accept_policy: AcceptPolicy<E>) -> Self {
+        self.accept_policy = accept_policy;
+        self
+    }
+
+    /// Sets how the server handles its `MakeService` failing to create a handler for a
+    /// connection.
+    pub fn with_make_service_policy(
+        mut self,
+        make_policy: MakeServicePolicy<K::MakeError>,
+    ) -> Self {
+        self.make_policy = make_policy;
+        self
+    }
+
+    /// Limits how many connections can be open at once. `on_limit` decides what happens to new
+    /// connections while the server is full.
+    pub fn with_max_connections(
+        mut self,
+        max_connections: usize,
+        on_limit: OnConnectionLimit,
+    ) -> Self {
+        self.limiter.set_limit(max_connections, on_limit);
+        self
+    }
+
+    /// Returns a handle to the number of accept errors the server has handled. Setting a new
+    /// accept policy afterwards starts new counts, so call this after
+    /// [`Server::with_accept_policy`].
+    pub fn accept_stats(&self) -> AcceptStats {
+        self.accept_policy.stats()
+    }
+
+    /// Returns a handle to the number of times the server's `MakeService` failed. Setting a new
+    /// policy afterwards starts new counts, so call this after
+    /// [`Server::with_make_service_policy`].
+    pub fn make_service_stats(&self) -> MakeServiceStats {
+        self.make_policy.stats()
+    }
+
+    /// Returns a handle to the server's current and peak connection counts.
+    pub fn connection_stats(&self) -> ConnectionStats {
+        self.limiter.stats()
+    }
+
+    /// Returns a handle for listing and closing the server's connections while it runs.
+    pub fn handle(&self) -> ServerHandle {
+        self.handle.clone()
+    }
+
+    /// Pings each client and closes connections that stop answering or sit idle.
+    pub fn with_keepalive(mut self, keepalive: Keepalive) -> Self {
+        self.keepalive = keepalive;
+        self
+    }
+
+    /// Runs `f` when a connection is accepted, right before it starts serving requests.
+    /// Connections rejected because of the connection limit don't run it.
+    pub fn on_connect<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&ConnectionInfo) + Send + Sync + 'static,
+    {
+        self.hooks.on_connect = Some(Arc::new(f));
+        self
+    }
+
+    /// Runs `f` when a connection that ran [`Server::on_connect`] ends, along with why it ended.
+    pub fn on_disconnect<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&ConnectionInfo, &DisconnectReason) + Send + Sync + 'static,
+    {
+        self.hooks.on_disconnect = Some(Arc::new(f));
+        self
+    }
+
+    /// Runs `f` each time the handler finishes a request.
+    pub fn on_request_complete<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&RequestOutcome) + Send + Sync + 'static,
+    {
+        self.hooks.on_request_complete = Some(Arc::new(f));
+        self
+    }
+
+    /// Reads the credentials of the peer process from each accepted connection's Unix socket.
+    /// Connections whose credentials can't be read are accepted without them.
+    ///
+    /// Transports that implement [`TransportInfo`], like IPC connections, already report their
+    /// credentials through [`Server::with_transport_info`]. This is for the ones that don't.
+    #[cfg(unix)]
+    pub fn with_peer_credentials<F>(self, socket: F) -> Self
+    where
+        F: for<'a> Fn(&'a I) -> std::os::fd::BorrowedFd<'a> + Send + Sync + 'static,
+    {
+        self.with_connection_info(move |conn, info| {
+            match PeerCredentials::from_socket(&socket(conn)) {
+                Ok(credentials) => info.with_credentials(credentials),
+                Err(e) => {
+                    warn!("Failed to read peer credentials: {e:?}");
+                    info
+                }
+            }
+        })
+    }
+}
+
 impl<K, H, S, I, E, Req, Res> Server<K, H, S, I, E, Pipeline, Req, Res>
 where
-    K: MakeService<(), Request<Req>, Service = H>,
-    K::MakeError: Debug,
+    K: MakeService<ConnectionInfo, Request<Req>, Service = H> + Send + 'static,
+    K::MakeError: Debug + Send + 'static,
+    K::Future: Send,
     H: tower::Service<Request<Req>, Response = Res> + Send + 'static,
     H::Future: Send + 'static,
-    H::Error: Debug + Send,
+    H::Error: Into<RemoteError> + Debug + Send,
     S: Stream<Item = Result<I, E>> + Send,
-    I: TryStream<Ok = Req> + Sink<Res> + Send + 'static,
+    I: TryStream<Ok = PipelineClientMessage<Req>>
+        + Sink<PipelineServerMessage<Res>>
+        + Send
+        + 'static,
     <I as TryStream>::Error: Debug,
-    <I as Sink<Res>>::Error: Debug,
-    E: Send,
+    <I as Sink<PipelineServerMessage<Res>>>::Error: Debug,
+    E: Debug + Send,
     Req: Send + Sync + 'static,
     Res: Send + Sync + 'static,
 {
@@ -50,45 +245,76 @@ where
         Self {
             incoming,
             handler,
+            inspect: None,
+            drain_timeout: None,
+            accept_policy: AcceptPolicy::default(),
+            make_policy: MakeServicePolicy::default(),
+            keepalive: Keepalive::default(),
+            hooks: Hooks::default(),
+            handle: ServerHandle::default(),
+            limiter: ConnectionLimiter::default(),
+            #[cfg(feature = "multiplex")]
+            request_limits: RequestLimits::default(),
             _phantom: Default::default(),
         }
     }
 
     async fn run_pipeline(mut self, mut context: ServiceContext) -> Result<(), BoxedError> {
         let incoming = self.incoming;
+        let make_service = Arc::new(Mutex::new(self.handler));
         futures::pin_mut!(incoming);
-        while let Ok(Some(Ok(stream))) = incoming
-            .next()
-            .cancel_on_shutdown(&context.cancellation_token())
-            .await
-        {
-            let handler = self
-                .handler
-                .make_service(())
+        while self.limiter.reserve(&context).await {
+            let Some(stream) = self
+                .accept_policy
+                .accept(&mut incoming, &context)
                 .await
-                .map_err(|e| format!("{e:?}"))?;
+                .map_err(|e| format!("Error accepting connection: {e:?}"))?
+            else {
+                break;
+            };
+            let info = ConnectionInfo::accepted(&stream, self.inspect.as_ref());
+            let Some(open_connection) = self.limiter.open() else {
+                warn!("Rejecting connection {}: connection limit reached", info.id);
+                context.add_service(("rpc_reject", move |context: ServiceContext| async move {
+                    let _ = reject(stream, PipelineServerMessage::Busy)
+                        .cancel_on_shutdown(&context.cancellation_token())
+                        .await;
+                    Ok(())
+                }));
+                continue;
+            };
+            let make_service = make_service.clone();
+            let make_policy = self.make_policy.clone();
+            let drain_timeout = self.drain_timeout;
+            let keepalive = self.keepalive.clone();
+            let hooks = self.hooks.clone();
+            let handle = self.handle.clone();
             context.add_service(("rpc_handler", move |context: ServiceContext| async move {
+                // Counts the connection as open until the handler finishes
+                let _open_connection = open_connection;
+                let Some(handler) = make_policy
+                    .make_service::<_, Request<Req>>(&make_service, &info, &context)
+                    .await
+                else {
+                    return Ok(());
+                };
+                let registration = handle.register(&info, &context.cancellation_token());
+                hooks.connected(&info);
                 let service = ServiceBuilder::default()
-                    .layer_fn(|inner| RequestService::new(context.clone(), inner))
+                    .layer_fn(|inner| {
+                        ResponseService::new(inner)
+                            .with_on_complete(info.id, hooks.on_request_complete.clone())
+                            .with_in_flight(registration.in_flight.clone())
+                    })
+                    .layer_fn(|inner| {
+                        RequestService::new(context.clone(), inner)
+                            .with_connection_info(info.clone())
+                    })
                     .service(handler);
 
-                if let Ok(res) = pipeline::Server::new(stream, service)
-                    .cancel_on_shutdown(&context.cancellation_token())
-                    .await
-                {
-                    return match res {
-                        Ok(()) => Ok(()),
-                        Err(pipeline::server::Error::Service(e)) => Err(format!("{e:?}"))?,
-                        Err(e) => {
-                            // Transport errors can happen if the client disconnects so they may
-                            // be expected
-                            info!("Transport failure: {e:?}");
-                            Ok(())
-                        }
-                    };
-                }
-
-                Ok(())
+                let connection = PipelineConnection::new(stream, service, keepalive);
+                let res = serve(connection, &registration.close, drain_timeout).await;
+                finish(res, &context, &registration, &info, &hooks)
             }));
         }
 
@@ -96,19 +322,56 @@ where
     }
 }
 
+type PipelineListeners<Req, Res> =
+    Listeners<PipelineClientMessage<Req>, PipelineServerMessage<Res>>;
+type PipelineListenerConnection<Req, Res> =
+    ListenerConnection<PipelineClientMessage<Req>, PipelineServerMessage<Res>>;
+
+impl<K, H, Req, Res>
+    Server<
+        K,
+        H,
+        PipelineListeners<Req, Res>,
+        PipelineListenerConnection<Req, Res>,
+        BoxError,
+        Pipeline,
+        Req,
+        Res,
+    >
+where
+    K: MakeService<ConnectionInfo, Request<Req>, Service = H> + Send + 'static,
+    K::MakeError: Debug + Send + 'static,
+    K::Future: Send,
+    H: tower::Service<Request<Req>, Response = Res> + Send + 'static,
+    H::Future: Send + 'static,
+    H::Error: Into<RemoteError> + Debug + Send,
+    Req: Send + Sync + 'static,
+    Res: Send + Sync + 'static,
+{
+    /// Serves the connections of several listeners. Each connection's [`ConnectionInfo`] records
+    /// the listener that accepted it, along with anything that listener's inspect function fills
+    /// in.
+    pub fn pipeline_listeners(listeners: PipelineListeners<Req, Res>, handler: K) -> Self {
+        Self::pipeline(listeners, handler).with_transport_info()
+    }
+}
+
 impl<K, H, S, I, E, Req, Res> BackgroundService for Server<K, H, S, I, E, Pipeline, Req, Res>
 where
-    K: MakeService<(), Request<Req>, Service = H> + Send,
-    K::MakeError: Debug,
+    K: MakeService<ConnectionInfo, Request<Req>, Service = H> + Send + 'static,
+    K::MakeError: Debug + Send + 'static,
     K::Future: Send,
     H: tower::Service<Request<Req>, Response = Res> + Send + 'static,
     H::Future: Send + 'static,
-    H::Error: Debug + Send,
+    H::Error: Into<RemoteError> + Debug + Send,
     S: Stream<Item = Result<I, E>> + Send,
-    I: TryStream<Ok = Req> + Sink<Res> + Send + 'static,
+    I: TryStream<Ok = PipelineClientMessage<Req>>
+        + Sink<PipelineServerMessage<Res>>
+        + Send
+        + 'static,
     <I as TryStream>::Error: Debug,
-    <I as Sink<Res>>::Error: Debug,
-    E: Send,
+    <I as Sink<PipelineServerMessage<Res>>>::Error: Debug,
+    E: Debug + Send,
     Req: Send + Sync + 'static,
     Res: Send + Sync + 'static,
 {
@@ -1,29 +1,37 @@
 use std::fmt::Debug;
+use std::sync::Arc;
 
 use background_service::error::BoxedError;
 use background_service::{BackgroundService, ServiceContext};
 use futures::{Sink, Stream, TryStream};
 use futures_cancel::FutureExt;
-use tokio_stream::StreamExt;
-use tokio_tower::multiplex;
-use tower::{MakeService, ServiceBuilder};
-use tracing::info;
+use tokio::sync::{Mutex, Semaphore};
+use tower::{BoxError, MakeService, ServiceBuilder};
+use tracing::warn;
 
-use crate::service::{MultiplexService, RequestService};
-use crate::{Multiplex, Request, Server, Tagged};
+use super::connection::{finish, reject, serve, MultiplexConnection};
+use crate::service::{RequestService, ResponseService};
+use crate::{
+    AcceptPolicy, ClientMessage, ConnectionInfo, ConnectionLimiter, FromCall, Hooks, IntoReply,
+    Keepalive, ListenerConnection, Listeners, MakeServicePolicy, Multiplex, Notifier,
+    OnRequestLimit, RemoteError, Request, RequestLimits, Server, ServerHandle, ServerMessage,
+};
 
-impl<K, H, S, I, E, Req, Res> Server<K, H, S, I, E, Multiplex, Req, Res>
+impl<K, H, S, I, E, In, Req, Res> Server<K, H, S, I, E, Multiplex, In, Res>
 where
-    K: MakeService<(), Request<Req>, Service = H>,
-    K::MakeError: Debug,
-    H: tower::Service<Request<Req>, Response = Res> + Send + 'static,
+    K: MakeService<ConnectionInfo, Request<In>, Service = H> + Send + 'static,
+    K::MakeError: Debug + Send + 'static,
+    K::Future: Send,
+    H: tower::Service<Request<In>> + Send + 'static,
+    H::Response: IntoReply<Res>,
     H::Future: Send + 'static,
-    H::Error: Send + Debug,
+    H::Error: Into<RemoteError> + Send + Debug,
     S: Stream<Item = Result<I, E>> + Send,
-    I: TryStream<Ok = Tagged<Req>> + Sink<Tagged<Res>> + Send + 'static,
+    I: TryStream<Ok = ClientMessage<Req>> + Sink<ServerMessage<Res>> + Send + 'static,
     <I as TryStream>::Error: Debug,
-    <I as Sink<Tagged<Res>>>::Error: Debug,
-    E: Send,
+    <I as Sink<ServerMessage<Res>>>::Error: Debug,
+    E: Debug + Send,
+    In: FromCall<Req> + Send + 'static,
     Req: Send + Sync + 'static,
     Res: Send + Sync + 'static,
 {
@@ -31,64 +39,176 @@ where
         Self {
             incoming,
             handler,
+            inspect: None,
+            drain_timeout: None,
+            accept_policy: AcceptPolicy::default(),
+            make_policy: MakeServicePolicy::default(),
+            keepalive: Keepalive::default(),
+            hooks: Hooks::default(),
+            handle: ServerHandle::default(),
+            limiter: ConnectionLimiter::default(),
+            request_limits: RequestLimits::default(),
             _phantom: Default::default(),
         }
     }
 
+    /// Limits how many requests each connection can have running at once. This includes casts
+    /// and responses that are still streaming.
+    pub fn with_max_requests_per_connection(mut self, max_requests: usize) -> Self {
+        self.request_limits.per_connection = Some(max_requests);
+        self
+    }
+
+    /// Limits how many requests can be running across every connection. Connections waiting for
+    /// room are served in the order they asked so one busy client can't starve the others.
+    pub fn with_max_requests(mut self, max_requests: usize) -> Self {
+        self.request_limits.global = Some(Arc::new(Semaphore::new(max_requests)));
+        self
+    }
+
+    /// Sets what happens to requests that arrive once a limit is reached. By default, they wait
+    /// in a queue for a request to finish, see [`OnRequestLimit::Backpressure`].
+    pub fn with_request_limit_policy(mut self, on_limit: OnRequestLimit) -> Self {
+        self.request_limits.on_limit = on_limit;
+        self
+    }
+
+    /// Sets how many requests each connection can have waiting for room to run. Defaults to 64,
+    /// and at least one request is always let in.
+    pub fn with_request_queue_depth(mut self, queue_depth: usize) -> Self {
+        self.request_limits.queue_depth = queue_depth.max(1);
+        self
+    }
+
+    /// Sets how many frames each connection can have waiting to be sent before it stops pulling
+    /// items from response streams and notifications, so a client that reads slowly slows them
+    /// down instead of everything being buffered. Defaults to 16, and is at least one.
+    pub fn with_send_buffer(mut self, frames: usize) -> Self {
+        self.request_limits.send_buffer = frames.max(1);
+        self
+    }
+
     async fn run_multiplex(mut self, mut context: ServiceContext) -> Result<(), BoxedError> {
         let incoming = self.incoming;
+        let make_service = Arc::new(Mutex::new(self.handler));
         futures::pin_mut!(incoming);
-        while let Ok(Some(Ok(stream))) = incoming
-            .next()
-            .cancel_on_shutdown(&context.cancellation_token())
-            .await
-        {
-            let handler = self
-                .handler
-                .make_service(())
+        while self.limiter.reserve(&context).await {
+            let Some(stream) = self
+                .accept_policy
+                .accept(&mut incoming, &context)
                 .await
-                .map_err(|e| format!("{e:?}"))?;
+                .map_err(|e| format!("Error accepting connection: {e:?}"))?
+            else {
+                break;
+            };
+            let info = ConnectionInfo::accepted(&stream, self.inspect.as_ref());
+            let Some(open_connection) = self.limiter.open() else {
+                warn!("Rejecting connection {}: connection limit reached", info.id);
+                context.add_service(("rpc_reject", move |context: ServiceContext| async move {
+                    let _ = reject(stream, ServerMessage::Busy)
+                        .cancel_on_shutdown(&context.cancellation_token())
+                        .await;
+                    Ok(())
+                }));
+                continue;
+            };
+            let make_service = make_service.clone();
+            let make_policy = self.make_policy.clone();
+            let drain_timeout = self.drain_timeout;
+            let request_limits = self.request_limits.clone();
+            let keepalive = self.keepalive.clone();
+            let hooks = self.hooks.clone();
+            let handle = self.handle.clone();
             context.add_service(("rpc_handler", move |context: ServiceContext| async move {
+                // Counts the connection as open until the handler finishes
+                let _open_connection = open_connection;
+                let Some(handler) = make_policy
+                    .make_service::<_, Request<In>>(&make_service, &info, &context)
+                    .await
+                else {
+                    return Ok(());
+                };
+                let registration = handle.register(&info, &context.cancellation_token());
+                hooks.connected(&info);
+                let (notifier, notifications) = Notifier::<Res>::channel();
                 let service = ServiceBuilder::default()
-                    .layer_fn(MultiplexService::new)
-                    .layer_fn(|inner| RequestService::new(context.clone(), inner))
+                    .layer_fn(|inner| {
+                        ResponseService::new(inner)
+                            .with_on_complete(info.id, hooks.on_request_complete.clone())
+                            .with_in_flight(registration.in_flight.clone())
+                    })
+                    .layer_fn(|inner| {
+                        RequestService::new(context.clone(), inner)
+                            .with_connection_info(info.clone())
+                            .with_notifier(notifier.clone())
+                    })
+                    .map_response(<H::Response as IntoReply<Res>>::into_reply)
                     .service(handler);
-                if let Ok(res) = multiplex::Server::new(stream, service)
-                    .cancel_on_shutdown(&context.cancellation_token())
-                    .await
-                {
-                    return match res {
-                        Ok(()) => Ok(()),
-                        Err(multiplex::server::Error::Service(e)) => Err(format!("{e:?}"))?,
-                        Err(e) => {
-                            // Transport errors can happen if the client disconnects so they may
-                            // be expected
-                            info!("Transport failure: {e:?}");
-                            Ok(())
-                        }
-                    };
-                }
-
-                Ok(())
+                let connection = MultiplexConnection::new(
+                    stream,
+                    service,
+                    notifications,
+                    request_limits,
+                    keepalive,
+                );
+                let res = serve(connection, &registration.close, drain_timeout).await;
+                finish(res, &context, &registration, &info, &hooks)
             }));
         }
         Ok(())
     }
 }
 
-impl<K, H, S, I, E, Req, Res> BackgroundService for Server<K, H, S, I, E, Multiplex, Req, Res>
+type MultiplexListeners<Req, Res> = Listeners<ClientMessage<Req>, ServerMessage<Res>>;
+type MultiplexListenerConnection<Req, Res> =
+    ListenerConnection<ClientMessage<Req>, ServerMessage<Res>>;
+
+impl<K, H, In, Req, Res>
+    Server<
+        K,
+        H,
+        MultiplexListeners<Req, Res>,
+        MultiplexListenerConnection<Req, Res>,
+        BoxError,
+        Multiplex,
+        In,
+        Res,
+    >
+where
+    K: MakeService<ConnectionInfo, Request<In>, Service = H> + Send + 'static,
+    K::MakeError: Debug + Send + 'static,
+    K::Future: Send,
+    H: tower::Service<Request<In>> + Send + 'static,
+    H::Response: IntoReply<Res>,
+    H::Future: Send + 'static,
+    H::Error: Into<RemoteError> + Send + Debug,
+    In: FromCall<Req> + Send + 'static,
+    Req: Send + Sync + 'static,
+    Res: Send + Sync + 'static,
+{
+    /// Serves the connections of several listeners. Each connection's [`ConnectionInfo`] records
+    /// the listener that accepted it, along with anything that listener's inspect function fills
+    /// in.
+    pub fn multiplex_listeners(listeners: MultiplexListeners<Req, Res>, handler: K) -> Self {
+        Self::multiplex(listeners, handler).with_transport_info()
+    }
+}
+
+impl<K, H, S, I, E, In, Req, Res> BackgroundService for Server<K, H, S, I, E, Multiplex, In, Res>
 where
-    K: MakeService<(), Request<Req>, Service = H> + Send,
-    K::MakeError: Debug,
+    K: MakeService<ConnectionInfo, Request<In>, Service = H> + Send + 'static,
+    K::MakeError: Debug + Send + 'static,
     K::Future: Send,
-    H: tower::Service<Request<Req>, Response = Res> + Send + 'static,
+    H: tower::Service<Request<In>> + Send + 'static,
+    H::Response: IntoReply<Res>,
     H::Future: Send + 'static,
-    H::Error: Send + Debug,
+    H::Error: Into<RemoteError> + Send + Debug,
     S: Stream<Item = Result<I, E>> + Send,
-    I: TryStream<Ok = Tagged<Req>> + Sink<Tagged<Res>> + Send + 'static,
+    I: TryStream<Ok = ClientMessage<Req>> + Sink<ServerMessage<Res>> + Send + 'static,
     <I as futures::TryStream>::Error: Debug,
-    <I as futures::Sink<Tagged<Res>>>::Error: Debug,
-    E: Send,
+    <I as futures::Sink<ServerMessage<Res>>>::Error: Debug,
+    E: Debug + Send,
+    In: FromCall<Req> + Send + 'static,
     Req: Send + Sync + 'static,
     Res: Send + Sync + 'static,
 {
@@ -0,0 +1,54 @@
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures::stream::BoxStream;
+use futures::{Stream, StreamExt};
+
+use crate::RemoteError;
+
+/// A response that's sent back as a sequence of items rather than a single value.
+pub struct ResponseStream<T> {
+    inner: BoxStream<'static, Result<T, RemoteError>>,
+}
+
+impl<T> ResponseStream<T> {
+    pub fn new<S, E>(stream: S) -> Self
+    where
+        S: Stream<Item = Result<T, E>> + Send + 'static,
+        E: Into<RemoteError>,
+    {
+        Self {
+            inner: stream.map(|item| item.map_err(Into::into)).boxed(),
+        }
+    }
+}
+
+impl<T> Stream for ResponseStream<T> {
+    type Item = Result<T, RemoteError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.inner.poll_next_unpin(cx)
+    }
+}
+
+pub enum Reply<T> {
+    Single(T),
+    Stream(ResponseStream<T>),
+}
+
+/// Converts a multiplex handler's response into either a single value or a stream of values.
+pub trait IntoReply<T> {
+    fn into_reply(self) -> Reply<T>;
+}
+
+impl<T> IntoReply<T> for T {
+    fn into_reply(self) -> Reply<T> {
+        Reply::Single(self)
+    }
+}
+
+impl<T> IntoReply<T> for ResponseStream<T> {
+    fn into_reply(self) -> Reply<T> {
+        Reply::Stream(self)
+    }
+}
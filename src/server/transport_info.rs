@@ -0,0 +1,89 @@
+use crate::ConnectionInfo;
+
+/// Transports that can describe the connection underneath them, so the server can fill in
+/// [`ConnectionInfo`] without a custom inspect function. See
+/// [`Server::with_transport_info`](crate::Server::with_transport_info).
+///
+/// Implemented for Tokio's TCP and Unix sockets and the connections accepted by
+/// `transport::ipc::Endpoint`, and forwarded through the framing layers the codecs wrap around
+/// them. Unix sockets and IPC connections also report the peer's credentials, read with
+/// `SO_PEERCRED` where it's available. Other transports can implement it to report their own
+/// details.
+pub trait TransportInfo {
+    fn connection_info(&self, info: ConnectionInfo) -> ConnectionInfo;
+}
+
+#[cfg(feature = "tcp")]
+impl TransportInfo for tokio::net::TcpStream {
+    fn connection_info(&self, info: ConnectionInfo) -> ConnectionInfo {
+        match self.peer_addr() {
+            Ok(peer_addr) => info.with_peer_addr(peer_addr),
+            Err(_) => info,
+        }
+    }
+}
+
+#[cfg(all(unix, feature = "ipc"))]
+impl TransportInfo for tokio::net::UnixStream {
+    fn connection_info(&self, mut info: ConnectionInfo) -> ConnectionInfo {
+        // The socket path is the IPC endpoint's name
+        if let Some(endpoint) = self
+            .local_addr()
+            .ok()
+            .and_then(|addr| addr.as_pathname().map(|path| path.display().to_string()))
+        {
+            info = info.with_endpoint(endpoint);
+        }
+        match crate::PeerCredentials::from_socket(self) {
+            Ok(credentials) => info.with_credentials(credentials),
+            Err(e) => {
+                tracing::warn!("Failed to read peer credentials: {e:?}");
+                info
+            }
+        }
+    }
+}
+
+// Connections accepted from `ipc::Endpoint` don't expose their socket path, so only the peer's
+// credentials are filled in
+#[cfg(all(unix, feature = "ipc"))]
+impl TransportInfo for transport_async::ipc::Connection {
+    fn connection_info(&self, info: ConnectionInfo) -> ConnectionInfo {
+        match crate::PeerCredentials::from_socket(self) {
+            Ok(credentials) => info.with_credentials(credentials),
+            Err(e) => {
+                tracing::warn!("Failed to read peer credentials: {e:?}");
+                info
+            }
+        }
+    }
+}
+
+#[cfg(feature = "codec")]
+impl<T, U> TransportInfo for tokio_util::codec::Framed<T, U>
+where
+    T: TransportInfo,
+{
+    fn connection_info(&self, info: ConnectionInfo) -> ConnectionInfo {
+        self.get_ref().connection_info(info)
+    }
+}
+
+#[cfg(feature = "codec")]
+impl<T, Item, SinkItem, Codec> TransportInfo for tokio_serde::Framed<T, Item, SinkItem, Codec>
+where
+    T: TransportInfo,
+{
+    fn connection_info(&self, info: ConnectionInfo) -> ConnectionInfo {
+        self.get_ref().connection_info(info)
+    }
+}
+
+impl<T, In, Out> TransportInfo for crate::RawFrames<T, In, Out>
+where
+    T: TransportInfo,
+{
+    fn connection_info(&self, info: ConnectionInfo) -> ConnectionInfo {
+        self.get_ref().connection_info(info)
+    }
+}
@@ -2,11 +2,7 @@
 mod request;
 #[cfg(feature = "server")]
 pub use request::*;
-#[cfg(all(feature = "multiplex", feature = "server"))]
-mod multiplex;
-#[cfg(all(feature = "multiplex", feature = "server"))]
-pub use multiplex::*;
-#[cfg(all(feature = "multiplex", feature = "client"))]
-mod demultiplex;
-#[cfg(all(feature = "multiplex", feature = "client"))]
-pub use demultiplex::*;
+#[cfg(feature = "server")]
+mod response;
+#[cfg(feature = "server")]
+pub use response::*;
@@ -5,11 +5,30 @@ use std::task::{Context, Poll};
 use background_service::ServiceContext;
 use futures::Future;
 
-use crate::Request;
+use crate::{ConnectionInfo, Extensions, Metadata, Request, RequestFrame, RequestId, Trailers};
+
+#[derive(Debug)]
+pub enum RequestError<E> {
+    Service(E),
+    DeadlineExceeded,
+}
+
+/// The handler's result along with any trailing metadata it set.
+pub struct HandlerResponse<Res, E> {
+    pub result: Result<Res, RequestError<E>>,
+    pub trailers: Metadata,
+}
+
+type HandlerFuture<Res, E> =
+    Pin<Box<dyn Future<Output = Result<HandlerResponse<Res, E>, RequestError<E>>> + Send>>;
 
 #[derive(Clone)]
 pub struct RequestService<S, Res> {
     context: ServiceContext,
+    // Cloned into every request
+    extensions: Extensions,
+    // Sequence number of the next request on a pipelined connection
+    next_sequence: u64,
     inner: S,
     _phantom: PhantomData<Res>,
 }
@@ -18,33 +37,118 @@ impl<S, Res> RequestService<S, Res> {
     pub fn new(context: ServiceContext, inner: S) -> Self {
         Self {
             context,
+            extensions: Extensions::default(),
+            next_sequence: 0,
             inner,
             _phantom: Default::default(),
         }
     }
+
+    pub fn with_connection_info(mut self, info: ConnectionInfo) -> Self {
+        #[cfg(unix)]
+        if let Some(credentials) = info.credentials {
+            self.extensions.insert(credentials);
+        }
+        self.extensions.insert(info);
+        self
+    }
+
+    #[cfg(feature = "multiplex")]
+    pub fn with_notifier<T>(mut self, notifier: crate::Notifier<T>) -> Self
+    where
+        T: Send + 'static,
+    {
+        self.extensions.insert(notifier);
+        self
+    }
+
+    fn call_handler<Req>(
+        &mut self,
+        id: Option<RequestId>,
+        req: RequestFrame<Req>,
+    ) -> HandlerFuture<Res, S::Error>
+    where
+        S: tower::Service<Request<Req>, Response = Res>,
+        S::Future: Send + 'static,
+    {
+        let deadline = req.deadline();
+        let trailers = Trailers::default();
+        let mut extensions = self.extensions.clone();
+        if let Some(id) = id {
+            extensions.insert(id);
+        }
+        let request = Request {
+            context: self.context.clone(),
+            deadline,
+            metadata: req.metadata,
+            trailers: trailers.clone(),
+            extensions,
+            value: req.value,
+        };
+        let res = self.inner.call(request);
+        Box::pin(async move {
+            Ok(HandlerResponse {
+                result: with_deadline(deadline, res).await,
+                trailers: trailers.take(),
+            })
+        })
+    }
 }
 
-impl<S, Req, Res> tower::Service<Req> for RequestService<S, Res>
+impl<S, Req, Res> tower::Service<RequestFrame<Req>> for RequestService<S, Res>
 where
     Req: Send,
     S: tower::Service<Request<Req>, Response = Res>,
     S::Future: Send + 'static,
 {
-    type Error = S::Error;
-    type Response = Res;
-    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+    // Handler errors are part of the response so they can be sent along with the trailers
+    type Error = RequestError<S::Error>;
+    type Response = HandlerResponse<Res, S::Error>;
+    type Future = HandlerFuture<Res, S::Error>;
     fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
-        self.inner.poll_ready(cx)
+        self.inner.poll_ready(cx).map_err(RequestError::Service)
     }
 
-    fn call(&mut self, req: Req) -> Self::Future {
-        let res = self.inner.call(Request {
-            context: self.context.clone(),
-            value: req,
-        });
-        Box::pin(async move {
-            let res = res.await?;
-            Ok(res)
-        })
+    fn call(&mut self, req: RequestFrame<Req>) -> Self::Future {
+        let id = RequestId::Sequence(self.next_sequence);
+        self.next_sequence += 1;
+        self.call_handler(Some(id), req)
+    }
+}
+
+// Multiplexed requests are identified by their tag. Casts don't have one.
+#[cfg(feature = "multiplex")]
+impl<S, Req, Res> tower::Service<(Option<crate::Tag>, RequestFrame<Req>)> for RequestService<S, Res>
+where
+    Req: Send,
+    S: tower::Service<Request<Req>, Response = Res>,
+    S::Future: Send + 'static,
+{
+    type Error = RequestError<S::Error>;
+    type Response = HandlerResponse<Res, S::Error>;
+    type Future = HandlerFuture<Res, S::Error>;
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(RequestError::Service)
+    }
+
+    fn call(&mut self, (tag, req): (Option<crate::Tag>, RequestFrame<Req>)) -> Self::Future {
+        self.call_handler(tag.map(RequestId::Tag), req)
+    }
+}
+
+async fn with_deadline<F, T, E>(
+    deadline: Option<std::time::Instant>,
+    res: F,
+) -> Result<T, RequestError<E>>
+where
+    F: Future<Output = Result<T, E>>,
+{
+    match deadline {
+        // Dropping the handler future on expiration cancels it
+        Some(deadline) => match tokio::time::timeout_at(deadline.into(), res).await {
+            Ok(res) => res.map_err(RequestError::Service),
+            Err(_) => Err(RequestError::DeadlineExceeded),
+        },
+        None => res.await.map_err(RequestError::Service),
     }
 }
@@ -0,0 +1,102 @@
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+use futures::Future;
+
+use crate::service::{HandlerResponse, RequestError};
+use crate::{
+    ConnectionId, OnRequestComplete, RemoteError, RemoteErrorKind, RequestOutcome, ResponseFrame,
+};
+
+#[derive(Clone)]
+pub struct ResponseService<S> {
+    inner: S,
+    on_complete: Option<(ConnectionId, OnRequestComplete)>,
+    in_flight: Option<Arc<AtomicUsize>>,
+}
+
+// Counts a request as in flight until the handler finishes or the request is cancelled
+struct InFlightGuard(Arc<AtomicUsize>);
+
+impl InFlightGuard {
+    fn new(in_flight: Arc<AtomicUsize>) -> Self {
+        in_flight.fetch_add(1, Ordering::Relaxed);
+        Self(in_flight)
+    }
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl<S> ResponseService<S> {
+    pub fn new(inner: S) -> Self {
+        Self {
+            inner,
+            on_complete: None,
+            in_flight: None,
+        }
+    }
+
+    /// Keeps `in_flight` up to date with the number of requests the handler is processing.
+    pub fn with_in_flight(mut self, in_flight: Arc<AtomicUsize>) -> Self {
+        self.in_flight = Some(in_flight);
+        self
+    }
+
+    /// Reports the outcome of each request on the connection once the handler finishes.
+    pub fn with_on_complete(
+        mut self,
+        connection_id: ConnectionId,
+        on_complete: Option<OnRequestComplete>,
+    ) -> Self {
+        self.on_complete = on_complete.map(|on_complete| (connection_id, on_complete));
+        self
+    }
+}
+
+impl<S, Req, Res, E> tower::Service<Req> for ResponseService<S>
+where
+    S: tower::Service<Req, Response = HandlerResponse<Res, E>, Error = RequestError<E>>,
+    E: Into<RemoteError>,
+    S::Future: Send + 'static,
+{
+    type Error = S::Error;
+    type Response = ResponseFrame<Res>;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Req) -> Self::Future {
+        let started = Instant::now();
+        let on_complete = self.on_complete.clone();
+        let in_flight = self.in_flight.clone().map(InFlightGuard::new);
+        let res = self.inner.call(req);
+        Box::pin(async move {
+            let _in_flight = in_flight;
+            let HandlerResponse { result, trailers } = res.await?;
+            let response = match result {
+                Ok(value) => ResponseFrame::ok(value),
+                Err(RequestError::Service(e)) => ResponseFrame::err(e.into()),
+                Err(RequestError::DeadlineExceeded) => ResponseFrame::err(RemoteError::new(
+                    RemoteErrorKind::DeadlineExceeded,
+                    "deadline exceeded",
+                )),
+            };
+            if let Some((connection_id, on_complete)) = on_complete {
+                on_complete(&RequestOutcome {
+                    connection_id,
+                    duration: started.elapsed(),
+                    error: response.result.as_ref().err().map(RemoteError::kind),
+                });
+            }
+            Ok::<_, S::Error>(response.with_metadata(trailers))
+        })
+    }
+}
@@ -1,8 +1,18 @@
 use std::fmt::Debug;
 
+/// Identifies a request on a multiplexed connection. Slot indices are reused once a request
+/// finishes, so the generation is used to tell a late response apart from one for a newer
+/// request in the same slot.
+#[cfg_attr(feature = "codec", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Tag {
+    pub(crate) index: u32,
+    pub(crate) generation: u32,
+}
+
 #[cfg_attr(feature = "codec", derive(serde::Serialize, serde::Deserialize))]
 #[derive(Clone, Debug)]
 pub struct Tagged<T> {
-    pub(crate) tag: usize,
+    pub(crate) tag: Tag,
     pub(crate) value: T,
 }
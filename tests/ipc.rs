@@ -0,0 +1,138 @@
+#![cfg(unix)]
+
+use std::convert::Infallible;
+use std::time::Duration;
+
+use background_service::BackgroundServiceManager;
+use bytes::{BufMut, Bytes, BytesMut};
+use futures::{future, SinkExt, StreamExt, TryStreamExt};
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+use tower::{service_fn, Service, ServiceExt};
+use tower_rpc::transport::codec::{
+    length_delimited_codec, Codec, CodecStream, LengthDelimitedCodec,
+};
+use tower_rpc::transport::ipc::{self, IpcSecurity, OnConflict, SecurityAttributes, ServerId};
+use tower_rpc::transport::{Bind, Connect};
+use tower_rpc::{
+    make_service_fn, make_service_fn_with_info, pipeline_codec, Client, PeerCredentials, RawFrames,
+    Request, Server,
+};
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+#[tokio::test]
+async fn peer_credentials_are_read_from_ipc_connections() {
+    let cancellation_token = CancellationToken::default();
+    let manager = BackgroundServiceManager::new(
+        cancellation_token.clone(),
+        background_service::Settings::default(),
+    );
+    let transport = ipc::Endpoint::bind(
+        ipc::EndpointParams::new(
+            ServerId("tower-rpc-test-credentials"),
+            SecurityAttributes::allow_everyone_create().unwrap(),
+            OnConflict::Overwrite,
+        )
+        .unwrap(),
+    )
+    .await
+    .unwrap();
+
+    // Each connection's credentials, as seen by the MakeService
+    let (credentials_tx, mut credentials_rx) = mpsc::unbounded_channel();
+    let server = Server::pipeline(
+        CodecStream::new(transport, pipeline_codec::<(), Option<u32>>(Codec::Bincode)),
+        make_service_fn_with_info(move |info| {
+            let _ = credentials_tx.send(info.credentials);
+            // Answers with the UID found in the request's extensions
+            service_fn(|req: Request<()>| {
+                let uid = req.extensions.get::<PeerCredentials>().map(|c| c.uid);
+                future::ready(Ok::<_, Infallible>(uid))
+            })
+        }),
+    )
+    .with_transport_info();
+    let mut context = manager.get_context();
+    context.add_service(server);
+
+    let connection = ipc::Connection::connect(
+        ipc::ConnectionParams::new(ServerId("tower-rpc-test-credentials")).unwrap(),
+    )
+    .await
+    .unwrap();
+    let mut client =
+        Client::new(pipeline_codec::<(), Option<u32>>(Codec::Bincode).client(connection))
+            .create_pipeline();
+    let uid = tokio::time::timeout(TIMEOUT, client.ready().await.unwrap().call(()))
+        .await
+        .unwrap()
+        .unwrap();
+
+    let credentials = tokio::time::timeout(TIMEOUT, credentials_rx.recv())
+        .await
+        .unwrap()
+        .unwrap()
+        .expect("connection info has no peer credentials");
+    assert_eq!(uid, Some(credentials.uid));
+    // The client is this process
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    assert_eq!(credentials.pid, Some(std::process::id() as i32));
+
+    cancellation_token.cancel();
+}
+
+#[tokio::test]
+async fn timeouts_too_large_for_a_deadline_are_ignored() {
+    let cancellation_token = CancellationToken::default();
+    let manager = BackgroundServiceManager::new(
+        cancellation_token.clone(),
+        background_service::Settings::default(),
+    );
+    let transport = ipc::Endpoint::bind(
+        ipc::EndpointParams::new(
+            ServerId("tower-rpc-test-max-timeout"),
+            SecurityAttributes::allow_everyone_create().unwrap(),
+            OnConflict::Overwrite,
+        )
+        .unwrap(),
+    )
+    .await
+    .unwrap();
+    let server = Server::pipeline(
+        CodecStream::new(transport, LengthDelimitedCodec).map_ok(RawFrames::server),
+        make_service_fn(|| {
+            service_fn(|req: Request<Bytes>| future::ready(Ok::<_, Infallible>(req.value)))
+        }),
+    );
+    let mut context = manager.get_context();
+    context.add_service(server);
+
+    let connection = ipc::Connection::connect(
+        ipc::ConnectionParams::new(ServerId("tower-rpc-test-max-timeout")).unwrap(),
+    )
+    .await
+    .unwrap();
+    let mut connection = length_delimited_codec(connection);
+
+    // A call with a timeout of `Duration::MAX` and no metadata, written the way `RawFrames` lays
+    // it out
+    let mut request = BytesMut::new();
+    request.put_u8(0);
+    request.put_u8(1);
+    request.put_u64(Duration::MAX.as_secs());
+    request.put_u32(Duration::MAX.subsec_nanos());
+    request.put_u32(0);
+    request.put_slice(b"ping");
+    connection.send(request.freeze()).await.unwrap();
+
+    let response = tokio::time::timeout(TIMEOUT, connection.next())
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    // A response with no metadata and the value sent back
+    assert_eq!(&response[..], b"\x02\x00\x00\x00\x00\x00ping");
+
+    cancellation_token.cancel();
+}
@@ -0,0 +1,385 @@
+use std::convert::Infallible;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use background_service::BackgroundServiceManager;
+use futures::{future, SinkExt, StreamExt};
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+use tower::{service_fn, Layer, Service, ServiceExt};
+use tower_rpc::transport::local;
+use tower_rpc::{
+    make_service_fn, CatchPanicLayer, Client, ClientError, ClientMessage, OnRequestLimit,
+    RemoteErrorKind, Request, RequestFrame, Server, ServerMessage,
+};
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+// Value that makes the handler wait until it's cancelled
+const FOREVER: u64 = u64::MAX;
+
+fn manager() -> (BackgroundServiceManager, CancellationToken) {
+    let cancellation_token = CancellationToken::default();
+    let manager = BackgroundServiceManager::new(
+        cancellation_token.clone(),
+        background_service::Settings::default(),
+    );
+    (manager, cancellation_token)
+}
+
+// Reports when the handler's future is dropped
+struct DropGuard(mpsc::UnboundedSender<()>);
+
+impl Drop for DropGuard {
+    fn drop(&mut self) {
+        let _ = self.0.send(());
+    }
+}
+
+// Answers with the request's value after waiting that many milliseconds, or never for `FOREVER`.
+// `dropped` is notified when the handler's future is dropped.
+async fn handle(req: Request<u64>, dropped: mpsc::UnboundedSender<()>) -> Result<u64, Infallible> {
+    let _guard = DropGuard(dropped);
+    if req.value == FOREVER {
+        future::pending::<()>().await;
+    }
+    tokio::time::sleep(Duration::from_millis(req.value)).await;
+    Ok(req.value)
+}
+
+#[tokio::test]
+async fn cancelled_calls_abort_the_handler_and_free_their_slot() {
+    let (manager, _cancellation_token) = manager();
+    let (transport, client_stream) = local::unbounded_channel();
+    let (dropped_tx, mut dropped_rx) = mpsc::unbounded_channel();
+    let server = Server::multiplex(
+        transport,
+        make_service_fn(move || {
+            let dropped = dropped_tx.clone();
+            service_fn(move |req| handle(req, dropped.clone()))
+        }),
+    )
+    .with_max_requests_per_connection(1);
+    let mut context = manager.get_context();
+    context.add_service(server);
+
+    let mut client = Client::new(client_stream.connect_unbounded().unwrap()).create_multiplex();
+    let call = client.ready().await.unwrap().call(FOREVER);
+    // Dropping the call cancels it
+    assert!(tokio::time::timeout(Duration::from_millis(50), call)
+        .await
+        .is_err());
+    tokio::time::timeout(TIMEOUT, dropped_rx.recv())
+        .await
+        .unwrap()
+        .unwrap();
+
+    // The connection only has room for one request, so this only runs if the cancelled one was
+    // cleaned up
+    let res = tokio::time::timeout(TIMEOUT, client.ready().await.unwrap().call(0))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(res, 0);
+}
+
+#[tokio::test]
+async fn casts_run_the_handler_without_a_reply() {
+    let (manager, _cancellation_token) = manager();
+    let (transport, client_stream) = local::unbounded_channel();
+    let (handled_tx, mut handled_rx) = mpsc::unbounded_channel();
+    let server = Server::multiplex(
+        transport,
+        make_service_fn(move || {
+            let handled = handled_tx.clone();
+            service_fn(move |req: Request<u64>| {
+                let _ = handled.send(req.value);
+                future::ready(Ok::<_, Infallible>(req.value))
+            })
+        }),
+    );
+    let mut context = manager.get_context();
+    context.add_service(server);
+
+    let mut connection = client_stream.connect_unbounded().unwrap();
+    connection
+        .send(ClientMessage::Cast(RequestFrame::new(1)))
+        .await
+        .unwrap();
+    let handled = tokio::time::timeout(TIMEOUT, handled_rx.recv())
+        .await
+        .unwrap();
+    assert_eq!(handled, Some(1));
+
+    // The cast finished before the ping was read, so a reply to it would arrive first
+    connection.send(ClientMessage::Ping).await.unwrap();
+    let message = tokio::time::timeout(TIMEOUT, connection.next())
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    assert!(
+        matches!(message, ServerMessage::<u64>::Pong),
+        "unexpected message: {message:?}"
+    );
+}
+
+#[tokio::test]
+async fn draining_server_finishes_running_requests_and_turns_away_new_ones() {
+    let (manager, cancellation_token) = manager();
+    let (transport, client_stream) = local::unbounded_channel();
+    let (dropped_tx, _dropped_rx) = mpsc::unbounded_channel();
+    let server = Server::multiplex(
+        transport,
+        make_service_fn(move || {
+            let dropped = dropped_tx.clone();
+            service_fn(move |req| handle(req, dropped.clone()))
+        }),
+    )
+    .with_drain_timeout(TIMEOUT);
+    let mut context = manager.get_context();
+    context.add_service(server);
+
+    let mut client = Client::new(client_stream.connect_unbounded().unwrap()).create_multiplex();
+    let running = client.ready().await.unwrap().call(100);
+    tokio::time::sleep(Duration::from_millis(20)).await;
+    cancellation_token.cancel();
+    tokio::time::sleep(Duration::from_millis(20)).await;
+
+    let err = match client.ready().await {
+        Ok(client) => tokio::time::timeout(TIMEOUT, client.call(0))
+            .await
+            .unwrap()
+            .unwrap_err(),
+        Err(e) => e,
+    };
+    assert!(matches!(err, ClientError::GoingAway), "{err:?}");
+    assert!(err.is_retryable());
+    assert_eq!(
+        tokio::time::timeout(TIMEOUT, running)
+            .await
+            .unwrap()
+            .unwrap(),
+        100
+    );
+}
+
+#[tokio::test]
+async fn requests_over_the_connection_limit_are_rejected() {
+    let (manager, _cancellation_token) = manager();
+    let (transport, client_stream) = local::unbounded_channel();
+    let (dropped_tx, _dropped_rx) = mpsc::unbounded_channel();
+    let server = Server::multiplex(
+        transport,
+        make_service_fn(move || {
+            let dropped = dropped_tx.clone();
+            service_fn(move |req| handle(req, dropped.clone()))
+        }),
+    )
+    .with_max_requests_per_connection(1)
+    .with_request_limit_policy(OnRequestLimit::Reject);
+    let mut context = manager.get_context();
+    context.add_service(server);
+
+    let mut client = Client::new(client_stream.connect_unbounded().unwrap()).create_multiplex();
+    let running = client.ready().await.unwrap().call(100);
+    let err = tokio::time::timeout(TIMEOUT, client.ready().await.unwrap().call(0))
+        .await
+        .unwrap()
+        .unwrap_err();
+    match err {
+        ClientError::Remote(e) => assert_eq!(e.kind(), RemoteErrorKind::Overloaded),
+        e => panic!("unexpected error: {e:?}"),
+    }
+
+    // The request that was already running isn't affected
+    assert_eq!(
+        tokio::time::timeout(TIMEOUT, running)
+            .await
+            .unwrap()
+            .unwrap(),
+        100
+    );
+}
+
+#[tokio::test]
+async fn cancellations_are_read_while_the_request_queue_is_full() {
+    let (manager, _cancellation_token) = manager();
+    let (transport, client_stream) = local::unbounded_channel();
+    let (dropped_tx, mut dropped_rx) = mpsc::unbounded_channel();
+    let server = Server::multiplex(
+        transport,
+        make_service_fn(move || {
+            let dropped = dropped_tx.clone();
+            service_fn(move |req| handle(req, dropped.clone()))
+        }),
+    )
+    .with_max_requests_per_connection(1)
+    .with_request_queue_depth(2);
+    let mut context = manager.get_context();
+    context.add_service(server);
+
+    let mut client = Client::new(client_stream.connect_unbounded().unwrap()).create_multiplex();
+    let running = client.ready().await.unwrap().call(FOREVER);
+    // Fills the connection's queue of requests waiting for room
+    let mut queued = Vec::new();
+    for _ in 0..2 {
+        queued.push(client.ready().await.unwrap().call(0));
+    }
+
+    // Dropping the call cancels it, which frees up room for the queued requests
+    drop(running);
+    tokio::time::timeout(TIMEOUT, dropped_rx.recv())
+        .await
+        .unwrap()
+        .unwrap();
+    let responses = tokio::time::timeout(TIMEOUT, future::try_join_all(queued))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(responses, [0; 2]);
+}
+
+#[tokio::test]
+async fn requests_beyond_a_full_queue_wait_instead_of_being_rejected() {
+    let (manager, _cancellation_token) = manager();
+    let (transport, client_stream) = local::unbounded_channel();
+    let (dropped_tx, _dropped_rx) = mpsc::unbounded_channel();
+    let server = Server::multiplex(
+        transport,
+        make_service_fn(move || {
+            let dropped = dropped_tx.clone();
+            service_fn(move |req| handle(req, dropped.clone()))
+        }),
+    )
+    .with_max_requests_per_connection(1)
+    .with_request_queue_depth(1);
+    let mut context = manager.get_context();
+    context.add_service(server);
+
+    let mut client = Client::new(client_stream.connect_unbounded().unwrap()).create_multiplex();
+    let mut calls = Vec::new();
+    // One running, one queued, and the rest held back until the queue has room
+    for value in [50, 0, 0, 0] {
+        calls.push(client.ready().await.unwrap().call(value));
+    }
+    let responses = tokio::time::timeout(TIMEOUT, future::try_join_all(calls))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(responses, [50, 0, 0, 0]);
+}
+
+#[tokio::test]
+async fn connections_take_turns_under_the_global_limit() {
+    let (manager, _cancellation_token) = manager();
+    let (transport, client_stream) = local::unbounded_channel();
+    let completed = Arc::new(AtomicU64::new(0));
+    // Answers with the order the request finished in
+    let server = Server::multiplex(
+        transport,
+        make_service_fn(move || {
+            let completed = completed.clone();
+            service_fn(move |req: Request<u64>| {
+                let completed = completed.clone();
+                async move {
+                    tokio::time::sleep(Duration::from_millis(req.value)).await;
+                    Ok::<_, Infallible>(completed.fetch_add(1, Ordering::SeqCst))
+                }
+            })
+        }),
+    )
+    .with_max_requests(1);
+    let mut context = manager.get_context();
+    context.add_service(server);
+
+    let mut busy = Client::new(client_stream.connect_unbounded().unwrap()).create_multiplex();
+    let mut calls = Vec::new();
+    for _ in 0..10 {
+        calls.push(busy.ready().await.unwrap().call(10));
+    }
+    tokio::time::sleep(Duration::from_millis(5)).await;
+
+    // Only waits behind the busy connection's running request and the one it's waiting to start
+    let mut quiet = Client::new(client_stream.connect_unbounded().unwrap()).create_multiplex();
+    let order = tokio::time::timeout(TIMEOUT, quiet.ready().await.unwrap().call(0))
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(order <= 2, "finished after {order} other requests");
+    tokio::time::timeout(TIMEOUT, future::try_join_all(calls))
+        .await
+        .unwrap()
+        .unwrap();
+}
+
+#[tokio::test]
+async fn handlers_can_notify_the_client() {
+    let (manager, _cancellation_token) = manager();
+    let (transport, client_stream) = local::unbounded_channel();
+    let server = Server::multiplex(
+        transport,
+        make_service_fn(|| {
+            service_fn(|req: Request<u64>| async move {
+                let notifier = req.notifier::<u64>().expect("notifier missing").clone();
+                notifier.notify(req.value + 1).await.unwrap();
+                Ok::<_, Infallible>(req.value)
+            })
+        }),
+    );
+    let mut context = manager.get_context();
+    context.add_service(server);
+
+    let (mut client, mut notifications) = Client::new(client_stream.connect_unbounded().unwrap())
+        .create_multiplex_with_notifications();
+    let res = tokio::time::timeout(TIMEOUT, client.ready().await.unwrap().call(1))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(res, 1);
+    let notification = tokio::time::timeout(TIMEOUT, notifications.next())
+        .await
+        .unwrap();
+    assert_eq!(notification, Some(2));
+}
+
+#[tokio::test]
+async fn panics_only_fail_the_request_that_caused_them() {
+    let (manager, _cancellation_token) = manager();
+    let (transport, client_stream) = local::unbounded_channel();
+    let server = Server::multiplex(
+        transport,
+        make_service_fn(|| {
+            CatchPanicLayer::new().layer(service_fn(|req: Request<u64>| async move {
+                if req.value == 0 {
+                    panic!("handler failed");
+                }
+                tokio::time::sleep(Duration::from_millis(req.value)).await;
+                Ok::<_, Infallible>(req.value)
+            }))
+        }),
+    );
+    let mut context = manager.get_context();
+    context.add_service(server);
+
+    let mut client = Client::new(client_stream.connect_unbounded().unwrap()).create_multiplex();
+    let running = client.ready().await.unwrap().call(50);
+    let err = tokio::time::timeout(TIMEOUT, client.ready().await.unwrap().call(0))
+        .await
+        .unwrap()
+        .unwrap_err();
+    match err {
+        ClientError::Remote(e) => assert_eq!(e.kind(), RemoteErrorKind::Internal),
+        e => panic!("unexpected error: {e:?}"),
+    }
+
+    // The other request on the connection is unaffected
+    assert_eq!(
+        tokio::time::timeout(TIMEOUT, running)
+            .await
+            .unwrap()
+            .unwrap(),
+        50
+    );
+}
@@ -0,0 +1,372 @@
+use std::convert::Infallible;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use background_service::BackgroundServiceManager;
+use futures::{future, SinkExt, StreamExt};
+use tokio::sync::{mpsc, Notify};
+use tokio_util::sync::CancellationToken;
+use tower::util::BoxService;
+#[cfg(unix)]
+use tower::Layer;
+use tower::{service_fn, BoxError, Service, ServiceExt};
+use tower_rpc::transport::local;
+use tower_rpc::{
+    make_service_fn, Backoff, Client, ClientError, ConnectionInfo, DisconnectReason, Keepalive,
+    MakeServicePolicy, OnConnectionLimit, PipelineClientMessage, PipelineServerMessage, Request,
+    RequestFrame, Server,
+};
+#[cfg(unix)]
+use tower_rpc::{PeerCredentials, RemoteError, RemoteErrorKind, UidFilterLayer};
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+fn manager() -> (BackgroundServiceManager, CancellationToken) {
+    let cancellation_token = CancellationToken::default();
+    let manager = BackgroundServiceManager::new(
+        cancellation_token.clone(),
+        background_service::Settings::default(),
+    );
+    (manager, cancellation_token)
+}
+
+// Answers with the request's value after waiting that many milliseconds
+fn sleepy_handler(
+    req: Request<u64>,
+) -> impl future::Future<Output = Result<u64, Infallible>> + Send {
+    async move {
+        tokio::time::sleep(Duration::from_millis(req.value)).await;
+        Ok(req.value)
+    }
+}
+
+#[tokio::test]
+async fn responses_arrive_in_request_order() {
+    let (manager, _cancellation_token) = manager();
+    let (transport, client_stream) = local::unbounded_channel();
+    let server = Server::pipeline(transport, make_service_fn(|| service_fn(sleepy_handler)));
+    let mut context = manager.get_context();
+    context.add_service(server);
+
+    let mut client = Client::new(client_stream.connect_unbounded().unwrap()).create_pipeline();
+    let mut calls = Vec::new();
+    for value in [30, 20, 10, 0] {
+        calls.push(client.ready().await.unwrap().call(value));
+    }
+    let responses = tokio::time::timeout(TIMEOUT, future::try_join_all(calls))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(responses, [30, 20, 10, 0]);
+}
+
+#[tokio::test]
+async fn casts_run_the_handler_without_a_reply() {
+    let (manager, _cancellation_token) = manager();
+    let (transport, client_stream) = local::unbounded_channel();
+    let (handled_tx, mut handled_rx) = mpsc::unbounded_channel();
+    let server = Server::pipeline(
+        transport,
+        make_service_fn(move || {
+            let handled = handled_tx.clone();
+            service_fn(move |req: Request<u64>| {
+                let _ = handled.send(req.value);
+                future::ready(Ok::<_, Infallible>(req.value))
+            })
+        }),
+    );
+    let mut context = manager.get_context();
+    context.add_service(server);
+
+    let mut connection = client_stream.connect_unbounded().unwrap();
+    connection
+        .send(PipelineClientMessage::Cast(RequestFrame::new(1)))
+        .await
+        .unwrap();
+    let handled = tokio::time::timeout(TIMEOUT, handled_rx.recv())
+        .await
+        .unwrap();
+    assert_eq!(handled, Some(1));
+
+    // Responses are sent in order, so a reply to the cast would arrive before this one
+    connection
+        .send(PipelineClientMessage::Call(RequestFrame::new(2)))
+        .await
+        .unwrap();
+    let message = tokio::time::timeout(TIMEOUT, connection.next())
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    match message {
+        PipelineServerMessage::Response(response) => assert_eq!(response.into_result(), Ok(2)),
+        message => panic!("unexpected message: {message:?}"),
+    }
+}
+
+#[tokio::test]
+async fn draining_server_finishes_running_requests_and_turns_away_new_ones() {
+    let (manager, cancellation_token) = manager();
+    let (transport, client_stream) = local::unbounded_channel();
+    let server = Server::pipeline(transport, make_service_fn(|| service_fn(sleepy_handler)))
+        .with_drain_timeout(TIMEOUT);
+    let mut context = manager.get_context();
+    context.add_service(server);
+
+    let mut client = Client::new(client_stream.connect_unbounded().unwrap()).create_pipeline();
+    let running = client.ready().await.unwrap().call(100);
+    tokio::time::sleep(Duration::from_millis(20)).await;
+    cancellation_token.cancel();
+
+    assert_eq!(
+        tokio::time::timeout(TIMEOUT, running)
+            .await
+            .unwrap()
+            .unwrap(),
+        100
+    );
+    let err = match client.ready().await {
+        Ok(client) => tokio::time::timeout(TIMEOUT, client.call(0))
+            .await
+            .unwrap()
+            .unwrap_err(),
+        Err(e) => e,
+    };
+    assert!(matches!(err, ClientError::GoingAway), "{err:?}");
+    assert!(err.is_retryable());
+}
+
+#[tokio::test]
+async fn connections_over_the_limit_are_told_the_server_is_busy() {
+    let (manager, _cancellation_token) = manager();
+    let (transport, client_stream) = local::unbounded_channel();
+    let server = Server::pipeline(transport, make_service_fn(|| service_fn(sleepy_handler)))
+        .with_max_connections(1, OnConnectionLimit::Reject);
+    let mut context = manager.get_context();
+    context.add_service(server);
+
+    let mut first = Client::new(client_stream.connect_unbounded().unwrap()).create_pipeline();
+    // Makes sure the first connection has been accepted before the second one is opened
+    assert_eq!(first.ready().await.unwrap().call(0).await.unwrap(), 0);
+
+    let mut second = Client::new(client_stream.connect_unbounded().unwrap()).create_pipeline();
+    let err = match second.ready().await {
+        Ok(client) => tokio::time::timeout(TIMEOUT, client.call(0))
+            .await
+            .unwrap()
+            .unwrap_err(),
+        Err(e) => e,
+    };
+    assert!(matches!(err, ClientError::Busy), "{err:?}");
+
+    // The first connection is unaffected
+    assert_eq!(first.ready().await.unwrap().call(1).await.unwrap(), 1);
+}
+
+#[tokio::test]
+async fn clients_that_stop_answering_pings_are_disconnected() {
+    let (manager, _cancellation_token) = manager();
+    let (transport, client_stream) = local::unbounded_channel();
+    let (reason_tx, mut reason_rx) = mpsc::unbounded_channel();
+    let server = Server::pipeline(transport, make_service_fn(|| service_fn(sleepy_handler)))
+        .with_keepalive(
+            Keepalive::default()
+                .with_ping_interval(Duration::from_millis(20))
+                .with_max_missed_pings(1),
+        )
+        .on_disconnect(move |_info, reason| {
+            let _ = reason_tx.send(reason.clone());
+        });
+    let mut context = manager.get_context();
+    context.add_service(server);
+
+    // Nothing reads from this connection, so the server's pings go unanswered
+    let _connection = client_stream.connect_unbounded().unwrap();
+    let reason = tokio::time::timeout(TIMEOUT, reason_rx.recv())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(reason, DisconnectReason::Unresponsive);
+}
+
+#[tokio::test]
+async fn retrying_one_connection_does_not_hold_up_others() {
+    let (manager, _cancellation_token) = manager();
+    let (transport, client_stream) = local::unbounded_channel();
+    let made = Arc::new(AtomicU32::new(0));
+    let make_service = service_fn(move |_: ConnectionInfo| {
+        let attempt = made.fetch_add(1, Ordering::SeqCst);
+        async move {
+            if attempt == 0 {
+                Err::<_, BoxError>("not ready yet".into())
+            } else {
+                Ok(service_fn(sleepy_handler))
+            }
+        }
+    });
+    let server = Server::pipeline(transport, make_service).with_make_service_policy(
+        MakeServicePolicy::default().with_retries(
+            1,
+            Backoff::default().with_initial_delay(Duration::from_secs(60)),
+        ),
+    );
+    let mut context = manager.get_context();
+    context.add_service(server);
+
+    let _first =
+        Client::new(client_stream.connect_unbounded().unwrap()).create_pipeline::<u64, u64>();
+    tokio::time::sleep(Duration::from_millis(20)).await;
+
+    let mut second = Client::new(client_stream.connect_unbounded().unwrap()).create_pipeline();
+    let res = tokio::time::timeout(TIMEOUT, second.ready().await.unwrap().call(0))
+        .await
+        .unwrap();
+    assert_eq!(res.unwrap(), 0);
+}
+
+#[tokio::test]
+async fn only_one_connection_checks_whether_the_service_has_recovered() {
+    let (manager, _cancellation_token) = manager();
+    let (transport, client_stream) = local::unbounded_channel();
+    let made = Arc::new(AtomicU32::new(0));
+    let recovered = Arc::new(Notify::new());
+    let make_service = service_fn({
+        let made = made.clone();
+        let recovered = recovered.clone();
+        move |_: ConnectionInfo| {
+            let attempt = made.fetch_add(1, Ordering::SeqCst);
+            let recovered = recovered.clone();
+            async move {
+                match attempt {
+                    0 => return Err::<_, BoxError>("not ready yet".into()),
+                    // Keeps the first connection after the cooldown busy checking the service
+                    1 => recovered.notified().await,
+                    _ => {}
+                }
+                Ok(service_fn(sleepy_handler))
+            }
+        }
+    });
+    let server = Server::pipeline(transport, make_service).with_make_service_policy(
+        MakeServicePolicy::default().with_circuit_breaker(1, Duration::from_millis(50)),
+    );
+    let mut context = manager.get_context();
+    context.add_service(server);
+
+    let _failed =
+        Client::new(client_stream.connect_unbounded().unwrap()).create_pipeline::<u64, u64>();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let mut probe = Client::new(client_stream.connect_unbounded().unwrap()).create_pipeline();
+    tokio::time::sleep(Duration::from_millis(20)).await;
+    let _dropped: Vec<_> = (0..2)
+        .map(|_| {
+            Client::new(client_stream.connect_unbounded().unwrap()).create_pipeline::<u64, u64>()
+        })
+        .collect();
+    tokio::time::sleep(Duration::from_millis(20)).await;
+    assert_eq!(made.load(Ordering::SeqCst), 2);
+
+    recovered.notify_one();
+    let res = tokio::time::timeout(TIMEOUT, probe.ready().await.unwrap().call(0))
+        .await
+        .unwrap();
+    assert_eq!(res.unwrap(), 0);
+}
+
+#[tokio::test]
+async fn rejected_connections_are_not_counted_as_failures() {
+    let (manager, _cancellation_token) = manager();
+    let (transport, client_stream) = local::unbounded_channel();
+    let make_service = service_fn(|_: ConnectionInfo| async {
+        Err::<BoxService<Request<u64>, u64, Infallible>, BoxError>("not allowed".into())
+    });
+    let policy = MakeServicePolicy::default()
+        .with_circuit_breaker(1, Duration::from_secs(60))
+        .with_rejections(|e: &BoxError| e.to_string() == "not allowed");
+    let stats = policy.stats();
+    let server = Server::pipeline(transport, make_service).with_make_service_policy(policy);
+    let mut context = manager.get_context();
+    context.add_service(server);
+
+    for _ in 0..2 {
+        let mut client = Client::new(client_stream.connect_unbounded().unwrap()).create_pipeline();
+        if let Ok(client) = client.ready().await {
+            let res = tokio::time::timeout(TIMEOUT, client.call(0u64))
+                .await
+                .unwrap();
+            assert!(res.is_err());
+        }
+    }
+    assert_eq!(stats.rejected(), 2);
+    assert_eq!(stats.total_failures(), 0);
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn requests_from_disallowed_peers_are_denied() {
+    let (manager, _cancellation_token) = manager();
+    let (transport, client_stream) = local::unbounded_channel();
+    // The local transport doesn't carry peer credentials, so every request is denied
+    let server = Server::pipeline(
+        transport,
+        make_service_fn(|| UidFilterLayer::current_user().layer(service_fn(sleepy_handler))),
+    );
+    let mut context = manager.get_context();
+    context.add_service(server);
+
+    let mut client = Client::new(client_stream.connect_unbounded().unwrap()).create_pipeline();
+    let err = tokio::time::timeout(TIMEOUT, client.ready().await.unwrap().call(0))
+        .await
+        .unwrap()
+        .unwrap_err();
+    match err {
+        ClientError::Remote(e) => assert_eq!(e.kind(), RemoteErrorKind::PermissionDenied),
+        e => panic!("unexpected error: {e:?}"),
+    }
+}
+
+#[cfg(unix)]
+#[derive(Debug)]
+struct BadInput;
+
+#[cfg(unix)]
+impl From<BadInput> for RemoteError {
+    fn from(_: BadInput) -> Self {
+        RemoteError::new(RemoteErrorKind::InvalidRequest, "bad input")
+    }
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn handler_errors_keep_their_kind_through_the_uid_filter() {
+    let (manager, _cancellation_token) = manager();
+    let (transport, client_stream) = local::unbounded_channel();
+    let credentials = PeerCredentials {
+        uid: 1000,
+        gid: 1000,
+        pid: None,
+    };
+    let server = Server::pipeline(
+        transport,
+        make_service_fn(|| {
+            UidFilterLayer::new([1000]).layer(service_fn(|_: Request<u64>| {
+                future::ready(Err::<u64, _>(BadInput))
+            }))
+        }),
+    )
+    .with_connection_info(move |_, info| info.with_credentials(credentials));
+    let mut context = manager.get_context();
+    context.add_service(server);
+
+    let mut client = Client::new(client_stream.connect_unbounded().unwrap()).create_pipeline();
+    let err = tokio::time::timeout(TIMEOUT, client.ready().await.unwrap().call(0u64))
+        .await
+        .unwrap()
+        .unwrap_err();
+    match err {
+        ClientError::Remote(e) => assert_eq!(e.kind(), RemoteErrorKind::InvalidRequest),
+        e => panic!("unexpected error: {e:?}"),
+    }
+}